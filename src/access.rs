@@ -0,0 +1,192 @@
+use std::{fmt, net::IpAddr, sync::Mutex};
+
+use anyhow::{anyhow, Result};
+use ipnet::IpNet;
+
+// listener 毎のルールに加えて全ての listener に適用されるルール
+static GLOBAL_ACCESS_RULES: once_cell::sync::Lazy<Mutex<AccessRules>> =
+    once_cell::sync::Lazy::new(|| Mutex::new(AccessRules::default()));
+
+/// Source address allowlist and denylist
+#[derive(Debug, Clone, Default)]
+pub struct AccessRules {
+    pub allow: Vec<IpNet>,
+    pub deny: Vec<IpNet>,
+}
+
+impl fmt::Display for AccessRules {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let join = |nets: &[IpNet]| {
+            nets.iter()
+                .map(|n| n.to_string())
+                .collect::<Vec<String>>()
+                .join(",")
+        };
+        match (self.allow.is_empty(), self.deny.is_empty()) {
+            (true, true) => write!(f, "-"),
+            (false, true) => write!(f, "allow {}", join(&self.allow)),
+            (true, false) => write!(f, "deny {}", join(&self.deny)),
+            (false, false) => write!(f, "allow {} deny {}", join(&self.allow), join(&self.deny)),
+        }
+    }
+}
+
+/// Parse a cidr like "10.10.0.0/16". A bare ip address is treated as a single host.
+pub fn parse_cidr(cidr: &str) -> Result<IpNet> {
+    if let Ok(net) = cidr.parse::<IpNet>() {
+        return Ok(net.trunc());
+    }
+    match cidr.parse::<IpAddr>() {
+        Ok(ip) => Ok(IpNet::from(ip)),
+        Err(_) => Err(anyhow!("\"{}\" is not a valid cidr", cidr)),
+    }
+}
+
+/// Check the source address against the rules of the listener and the global rules.
+/// Deny rules take precedence, and when any allow rule exists the address has to match one of them.
+pub fn is_allowed(ip: IpAddr, rules: &AccessRules) -> Result<bool> {
+    let global = match GLOBAL_ACCESS_RULES.lock() {
+        Ok(g) => g,
+        Err(e) => return Err(anyhow!(e.to_string())),
+    };
+    Ok(check(ip, rules, &global))
+}
+
+fn check(ip: IpAddr, rules: &AccessRules, global: &AccessRules) -> bool {
+    // connections to a listener bound to [::] come from ipv4-mapped addresses
+    let ip = ip.to_canonical();
+
+    if rules
+        .deny
+        .iter()
+        .chain(global.deny.iter())
+        .any(|n| n.contains(&ip))
+    {
+        return false;
+    }
+
+    if rules.allow.is_empty() && global.allow.is_empty() {
+        return true;
+    }
+
+    rules
+        .allow
+        .iter()
+        .chain(global.allow.iter())
+        .any(|n| n.contains(&ip))
+}
+
+pub fn add_global_allow(net: IpNet) -> Result<()> {
+    match GLOBAL_ACCESS_RULES.lock() {
+        Ok(mut g) => g.allow.push(net),
+        Err(e) => return Err(anyhow!(e.to_string())),
+    }
+    Ok(())
+}
+
+pub fn add_global_deny(net: IpNet) -> Result<()> {
+    match GLOBAL_ACCESS_RULES.lock() {
+        Ok(mut g) => g.deny.push(net),
+        Err(e) => return Err(anyhow!(e.to_string())),
+    }
+    Ok(())
+}
+
+pub fn clear_global_rules() -> Result<()> {
+    match GLOBAL_ACCESS_RULES.lock() {
+        Ok(mut g) => *g = AccessRules::default(),
+        Err(e) => return Err(anyhow!(e.to_string())),
+    }
+    Ok(())
+}
+
+pub fn get_global_rules() -> Result<AccessRules> {
+    match GLOBAL_ACCESS_RULES.lock() {
+        Ok(g) => Ok(g.clone()),
+        Err(e) => Err(anyhow!(e.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(allow: &[&str], deny: &[&str]) -> AccessRules {
+        AccessRules {
+            allow: allow.iter().map(|c| parse_cidr(c).unwrap()).collect(),
+            deny: deny.iter().map(|c| parse_cidr(c).unwrap()).collect(),
+        }
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_cidr_truncates_host_bits() {
+        assert_eq!(
+            parse_cidr("10.10.3.4/16").unwrap().to_string(),
+            "10.10.0.0/16"
+        );
+        assert_eq!(parse_cidr("fd00::1/8").unwrap().to_string(), "fd00::/8");
+    }
+
+    #[test]
+    fn parse_cidr_takes_a_bare_address_as_a_host() {
+        assert_eq!(parse_cidr("10.0.0.1").unwrap().to_string(), "10.0.0.1/32");
+        assert_eq!(parse_cidr("::1").unwrap().to_string(), "::1/128");
+    }
+
+    #[test]
+    fn parse_cidr_rejects_garbage() {
+        assert!(parse_cidr("10.0.0.0/33").is_err());
+        assert!(parse_cidr("10.0.0").is_err());
+        assert!(parse_cidr("tun0").is_err());
+    }
+
+    #[test]
+    fn everything_is_allowed_without_rules() {
+        let none = AccessRules::default();
+        assert!(check(ip("203.0.113.5"), &none, &none));
+        assert!(check(ip("2001:db8::5"), &none, &none));
+    }
+
+    #[test]
+    fn deny_takes_precedence_over_allow() {
+        let none = AccessRules::default();
+        let listener = rules(&["10.0.0.0/8"], &["10.0.5.0/24"]);
+        assert!(check(ip("10.1.2.3"), &listener, &none));
+        assert!(!check(ip("10.0.5.7"), &listener, &none));
+    }
+
+    #[test]
+    fn allow_rules_make_other_addresses_denied() {
+        let none = AccessRules::default();
+        let listener = rules(&["10.0.0.0/8"], &[]);
+        assert!(!check(ip("192.168.0.1"), &listener, &none));
+    }
+
+    #[test]
+    fn global_rules_apply_to_every_listener() {
+        let global_deny = rules(&[], &["10.0.5.0/24"]);
+        let listener = rules(&["10.0.0.0/8"], &[]);
+        assert!(!check(ip("10.0.5.7"), &listener, &global_deny));
+
+        // a global allow rule adds to the allow rules of the listener
+        let global_allow = rules(&["192.168.0.0/16"], &[]);
+        assert!(check(ip("192.168.0.1"), &listener, &global_allow));
+        assert!(check(ip("10.1.2.3"), &listener, &global_allow));
+        assert!(!check(ip("172.16.0.1"), &listener, &global_allow));
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_match_ipv4_rules() {
+        let none = AccessRules::default();
+        let listener = rules(&[], &["10.0.5.0/24"]);
+        assert!(!check(ip("::ffff:10.0.5.7"), &listener, &none));
+
+        let listener = rules(&["10.0.0.0/8"], &[]);
+        assert!(check(ip("::ffff:10.1.2.3"), &listener, &none));
+        assert!(!check(ip("::ffff:192.168.0.1"), &listener, &none));
+    }
+}
@@ -0,0 +1,75 @@
+use anyhow::anyhow;
+use log::info;
+use tokio::net::lookup_host;
+
+use crate::util::{print_error, tidy_usage};
+
+use super::CommandReturns;
+
+pub struct Connect {}
+
+impl super::Command for Connect {
+    fn name() -> String {
+        "connect".to_string()
+    }
+
+    fn info() -> String {
+        "Connect to a bind shell".to_string()
+    }
+
+    async fn exec(args: super::CommandArgs) -> super::CommandReturns {
+        if args.args.len() != 2 {
+            Self::help();
+            return CommandReturns::new(
+                args.args.len() == 1 && args.args[0] == "help",
+                args.manager,
+            );
+        }
+
+        let port = match args.args[1].parse::<u16>() {
+            Ok(port) => port,
+            Err(e) => {
+                print_error("failed to parse an arg as port", anyhow!(e));
+                return CommandReturns::new(false, args.manager);
+            }
+        };
+
+        let host = args.args[0]
+            .strip_prefix('[')
+            .and_then(|h| h.strip_suffix(']'))
+            .unwrap_or(&args.args[0]);
+
+        let address = match lookup_host((host, port)).await {
+            Ok(mut addresses) => match addresses.next() {
+                Some(a) => a,
+                None => {
+                    print_error("failed to resolve the host", anyhow!("no address found"));
+                    return CommandReturns::new(false, args.manager);
+                }
+            },
+            Err(e) => {
+                print_error("failed to resolve the host", anyhow!(e));
+                return CommandReturns::new(false, args.manager);
+            }
+        };
+
+        let mut manager = args.manager;
+        manager.current_session_id = Some(match crate::session::connect_session(address).await {
+            Ok(s) => s,
+            Err(e) => {
+                print_error(&format!("failed to connect to {}", address), e);
+                return CommandReturns::new(false, manager);
+            }
+        });
+
+        CommandReturns::new(true, manager)
+    }
+
+    fn help() {
+        info!("usage:");
+        println!(
+            "  {}",
+            tidy_usage("connect <host> <port>", "Connect to a bind shell")
+        );
+    }
+}
@@ -1,7 +1,19 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
 use anyhow::anyhow;
 use log::info;
 
-use crate::util::{print_error, tidy_usage};
+use crate::{
+    access::parse_cidr,
+    listener::{make_listener_table, ListenerOptions},
+    tls::TlsIdentity,
+    util::{print_error, resolve_bind_address, tidy_usage},
+};
 
 use super::CommandReturns;
 
@@ -17,14 +29,22 @@ impl super::Command for Listen {
     }
 
     async fn exec(args: super::CommandArgs) -> super::CommandReturns {
-        if args.args.is_empty()
-            || (args.args.len() == 1 && args.args[0] == "help")
-            || args.args.len() > 2
-        {
+        if args.args.is_empty() || (args.args.len() == 1 && args.args[0] == "help") {
             Self::help();
             return CommandReturns::new(true, args.manager);
         }
 
+        if args.args.len() == 1 && args.args[0] == "-l" {
+            match make_listener_table() {
+                Ok(t) => println!("{}", t),
+                Err(e) => {
+                    print_error("failed to make listener table", e);
+                    return CommandReturns::new(false, args.manager);
+                }
+            }
+            return CommandReturns::new(true, args.manager);
+        }
+
         let port = match args.args[0].parse::<u16>() {
             Ok(port) => port,
             Err(e) => {
@@ -33,14 +53,163 @@ impl super::Command for Listen {
             }
         };
 
-        let mut manager = args.manager;
-        manager.current_session_id = Some(match crate::session::new_session(port).await {
-            Ok(s) => s,
-            Err(e) => {
-                print_error("failed to create a new session", e);
-                return CommandReturns::new(false, manager);
+        let mut bind_ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let mut is_background = false;
+        let mut listener_options = ListenerOptions::default();
+        let mut use_tls = false;
+        let mut cert_path = None;
+        let mut key_path = None;
+        let mut options = args.args[1..].iter();
+        while let Some(option) = options.next() {
+            match option.as_str() {
+                "-b" => {
+                    let address = match options.next() {
+                        Some(a) => a,
+                        None => {
+                            Self::help();
+                            return CommandReturns::new(false, args.manager);
+                        }
+                    };
+                    bind_ip = match resolve_bind_address(address) {
+                        Ok(ip) => ip,
+                        Err(e) => {
+                            print_error("failed to resolve the bind address", e);
+                            return CommandReturns::new(false, args.manager);
+                        }
+                    };
+                }
+                "-bg" => is_background = true,
+                "-p" => {
+                    listener_options.persistent = true;
+                    is_background = true;
+                }
+                "-n" => {
+                    let max = match options.next().map(|n| n.parse::<usize>()) {
+                        Some(Ok(n)) if n > 0 => n,
+                        _ => {
+                            Self::help();
+                            return CommandReturns::new(false, args.manager);
+                        }
+                    };
+                    listener_options.max_connections = Some(max);
+                    is_background = true;
+                }
+                "-t" => {
+                    let secs = match options.next().map(|t| t.parse::<u64>()) {
+                        Some(Ok(t)) => t,
+                        _ => {
+                            Self::help();
+                            return CommandReturns::new(false, args.manager);
+                        }
+                    };
+                    listener_options.idle_timeout = Some(Duration::from_secs(secs));
+                    is_background = true;
+                }
+                "-tls" => use_tls = true,
+                "-mux" => {
+                    listener_options.mux = true;
+                    listener_options.persistent = true;
+                    is_background = true;
+                    use_tls = true;
+                }
+                "-serve" => {
+                    listener_options.serve_root = match options.next() {
+                        Some(p) => Some(PathBuf::from(p)),
+                        None => {
+                            Self::help();
+                            return CommandReturns::new(false, args.manager);
+                        }
+                    };
+                }
+                "-allow" | "-deny" => {
+                    let net = match options.next().map(|c| parse_cidr(c)) {
+                        Some(Ok(n)) => n,
+                        Some(Err(e)) => {
+                            print_error("failed to parse an arg as cidr", e);
+                            return CommandReturns::new(false, args.manager);
+                        }
+                        None => {
+                            Self::help();
+                            return CommandReturns::new(false, args.manager);
+                        }
+                    };
+                    if option == "-allow" {
+                        listener_options.access.allow.push(net);
+                    } else {
+                        listener_options.access.deny.push(net);
+                    }
+                }
+                "-cert" | "-key" => {
+                    let path = match options.next() {
+                        Some(p) => PathBuf::from(p),
+                        None => {
+                            Self::help();
+                            return CommandReturns::new(false, args.manager);
+                        }
+                    };
+                    if option == "-cert" {
+                        cert_path = Some(path);
+                    } else {
+                        key_path = Some(path);
+                    }
+                    use_tls = true;
+                }
+                _ => {
+                    Self::help();
+                    return CommandReturns::new(false, args.manager);
+                }
             }
-        });
+        }
+
+        let bind_address = SocketAddr::new(bind_ip, port);
+
+        if use_tls {
+            let identity = match (&cert_path, &key_path) {
+                (Some(cert_path), Some(key_path)) => {
+                    TlsIdentity::from_pem_files(cert_path, key_path)
+                }
+                (None, None) => TlsIdentity::self_signed(),
+                _ => {
+                    print_error(
+                        "failed to load the certificate",
+                        anyhow!("both -cert and -key are required"),
+                    );
+                    return CommandReturns::new(false, args.manager);
+                }
+            };
+            match identity {
+                Ok(i) => listener_options.tls = Some(Arc::new(i)),
+                Err(e) => {
+                    print_error("failed to prepare tls", e);
+                    return CommandReturns::new(false, args.manager);
+                }
+            }
+        }
+
+        if is_background {
+            if let Err(e) = crate::listener::spawn_listener(bind_address, listener_options).await {
+                print_error("failed to start a background listener", e);
+                return CommandReturns::new(false, args.manager);
+            }
+            return CommandReturns::new(true, args.manager);
+        }
+
+        let mut manager = args.manager;
+        manager.current_session_id = Some(
+            match crate::session::new_session(
+                bind_address,
+                listener_options.tls.as_deref(),
+                &listener_options.access,
+            )
+            .await
+            {
+                Ok(s) => s,
+                Err(e) => {
+                    print_error("failed to create a new session", e);
+                    return CommandReturns::new(false, manager);
+                }
+            },
+        );
 
         CommandReturns::new(true, manager)
     }
@@ -52,5 +221,79 @@ impl super::Command for Listen {
             "  {}",
             tidy_usage("listen <port> -bg", "Listen on a port in background")
         );
+        println!(
+            "  {}",
+            tidy_usage(
+                "listen <port> -b <ip|if>",
+                "Listen on an address or interface (default: 127.0.0.1, 0.0.0.0 for all)"
+            )
+        );
+        println!(
+            "  {}",
+            tidy_usage(
+                "listen <port> -p",
+                "Keep listening in background and open a session per connection"
+            )
+        );
+        println!(
+            "  {}",
+            tidy_usage(
+                "listen <port> -n <max>",
+                "Stop listening after <max> connections"
+            )
+        );
+        println!(
+            "  {}",
+            tidy_usage(
+                "listen <port> -t <sec>",
+                "Stop listening after <sec> seconds without connections"
+            )
+        );
+        println!(
+            "  {}",
+            tidy_usage(
+                "listen <port> -tls",
+                "Wrap connections with tls using a self-signed certificate"
+            )
+        );
+        println!(
+            "  {}",
+            tidy_usage(
+                "listen <port> -tls -cert <pem> -key <pem>",
+                "Wrap connections with tls using the certificate"
+            )
+        );
+        println!(
+            "  {}",
+            tidy_usage(
+                "listen <port> -mux",
+                "Serve tls shells, http and plain shells on one port in background"
+            )
+        );
+        println!(
+            "  {}",
+            tidy_usage(
+                "listen <port> -mux -serve <dir>",
+                "Serve files in the directory to http requests (/sayo.sh is a payload)"
+            )
+        );
+        println!(
+            "  {}",
+            tidy_usage(
+                "listen <port> -allow <cidr>",
+                "Accept connections only from the network (repeatable)"
+            )
+        );
+        println!(
+            "  {}",
+            tidy_usage(
+                "listen <port> -deny <cidr>",
+                "Reject connections from the network (repeatable)"
+            )
+        );
+        println!(
+            "  {}",
+            tidy_usage("listen -l", "List listeners running in background")
+        );
     }
 }
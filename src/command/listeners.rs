@@ -0,0 +1,153 @@
+use anyhow::anyhow;
+
+use crate::{
+    access::{self, parse_cidr},
+    listener::{self, make_listener_table},
+    util::print_error,
+};
+
+use super::CommandReturns;
+
+pub struct Listeners {}
+
+impl super::Command for Listeners {
+    fn name() -> String {
+        "listeners".to_string()
+    }
+
+    fn info() -> String {
+        "Manage background listeners".to_string()
+    }
+
+    async fn exec(args: super::CommandArgs) -> super::CommandReturns {
+        // print listener list
+        if args.args.is_empty() {
+            let table = match make_listener_table() {
+                Ok(t) => t,
+                Err(e) => {
+                    print_error("failed to make listener table", e);
+                    return CommandReturns::new(false, args.manager);
+                }
+            };
+
+            println!("{}", table);
+            return CommandReturns::new(true, args.manager);
+        }
+
+        // global source address rules
+        match args.args[0].as_str() {
+            "rules" if args.args.len() == 1 => {
+                match access::get_global_rules() {
+                    Ok(r) => println!("global rules: {}", r),
+                    Err(e) => {
+                        print_error("failed to get global rules", e);
+                        return CommandReturns::new(false, args.manager);
+                    }
+                }
+                return CommandReturns::new(true, args.manager);
+            }
+            "clear-rules" if args.args.len() == 1 => {
+                if let Err(e) = access::clear_global_rules() {
+                    print_error("failed to clear global rules", e);
+                    return CommandReturns::new(false, args.manager);
+                }
+                return CommandReturns::new(true, args.manager);
+            }
+            "allow" | "deny" if args.args.len() == 2 => {
+                let net = match parse_cidr(&args.args[1]) {
+                    Ok(n) => n,
+                    Err(e) => {
+                        print_error("failed to parse an arg as cidr", e);
+                        return CommandReturns::new(false, args.manager);
+                    }
+                };
+                let result = if args.args[0] == "allow" {
+                    access::add_global_allow(net)
+                } else {
+                    access::add_global_deny(net)
+                };
+                if let Err(e) = result {
+                    print_error("failed to add a global rule", e);
+                    return CommandReturns::new(false, args.manager);
+                }
+                return CommandReturns::new(true, args.manager);
+            }
+            _ => {}
+        }
+
+        // print help message
+        if args.args.len() != 2 {
+            Self::help();
+            return CommandReturns::new(args.args[0] == "help", args.manager);
+        }
+
+        let id = match args.args[1].parse::<u16>() {
+            Ok(num) => num,
+            Err(e) => {
+                print_error("failed to parse an arg as id", anyhow!(e.to_string()));
+                Self::help();
+                return CommandReturns::new(false, args.manager);
+            }
+        };
+
+        let result = match args.args[0].as_str() {
+            "stop" => listener::stop_listener(id).await,
+            "restart" => listener::restart_listener(id).await,
+            "remove" => listener::remove_listener(id).await,
+            _ => {
+                Self::help();
+                return CommandReturns::new(false, args.manager);
+            }
+        };
+
+        if let Err(e) = result {
+            print_error(&format!("failed to {} listener {}", args.args[0], id), e);
+            return CommandReturns::new(false, args.manager);
+        }
+
+        CommandReturns::new(true, args.manager)
+    }
+
+    fn help() {
+        use crate::util::tidy_usage;
+        println!("Usage:");
+        println!("\t{}", tidy_usage("listeners", "List all listeners"));
+        println!(
+            "\t{}",
+            tidy_usage(
+                "listeners stop <id>",
+                "Stop a listener (opened sessions are kept)"
+            )
+        );
+        println!(
+            "\t{}",
+            tidy_usage(
+                "listeners restart <id>",
+                "Start a listener again with the same options"
+            )
+        );
+        println!(
+            "\t{}",
+            tidy_usage("listeners remove <id>", "Stop a listener and remove it")
+        );
+        println!(
+            "\t{}",
+            tidy_usage(
+                "listeners allow <cidr>",
+                "Accept connections only from the network on all listeners"
+            )
+        );
+        println!(
+            "\t{}",
+            tidy_usage(
+                "listeners deny <cidr>",
+                "Reject connections from the network on all listeners"
+            )
+        );
+        println!("\t{}", tidy_usage("listeners rules", "Show global rules"));
+        println!(
+            "\t{}",
+            tidy_usage("listeners clear-rules", "Remove all global rules")
+        );
+    }
+}
@@ -1,14 +1,25 @@
-use self::{exit::Exit, listen::Listen, sessions::Sessions};
+use self::{
+    connect::Connect, exit::Exit, listen::Listen, listeners::Listeners, sessions::Sessions,
+    set::Set, upgrade::Upgrade,
+};
 
+mod connect;
 mod exit;
 mod listen;
+mod listeners;
 mod sessions;
+mod set;
+mod upgrade;
 
 pub async fn execute_command(command: &str, args: CommandArgs) -> CommandReturns {
     match command {
+        "connect" => Connect::exec(args).await,
         "exit" => Exit::exec(args).await,
         "listen" => Listen::exec(args).await,
+        "listeners" => Listeners::exec(args).await,
         "sessions" => Sessions::exec(args).await,
+        "set" => Set::exec(args).await,
+        "upgrade" => Upgrade::exec(args).await,
         "help" => Help::exec(args).await,
         _ => {
             println!("Unknown command: {}", command);
@@ -20,6 +31,13 @@ pub async fn execute_command(command: &str, args: CommandArgs) -> CommandReturns
 
 pub fn display_help() {
     println!("Usage:");
+    println!(
+        "  {}{}{}",
+        Connect::name(),
+        " ".repeat(20 - Connect::name().len()),
+        Connect::info()
+    );
+
     println!(
         "  {}{}{}",
         Exit::name(),
@@ -34,12 +52,33 @@ pub fn display_help() {
         Listen::info()
     );
 
+    println!(
+        "  {}{}{}",
+        Listeners::name(),
+        " ".repeat(20 - Listeners::name().len()),
+        Listeners::info()
+    );
+
     println!(
         "  {}{}{}",
         Sessions::name(),
         " ".repeat(20 - Sessions::name().len()),
         Sessions::info()
     );
+
+    println!(
+        "  {}{}{}",
+        Set::name(),
+        " ".repeat(20 - Set::name().len()),
+        Set::info()
+    );
+
+    println!(
+        "  {}{}{}",
+        Upgrade::name(),
+        " ".repeat(20 - Upgrade::name().len()),
+        Upgrade::info()
+    );
 }
 
 struct Help {}
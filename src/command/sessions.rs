@@ -1,11 +1,25 @@
 use anyhow::anyhow;
+use log::info;
 
 use crate::{
-    session::{self, make_session_table},
+    display::DisplayMode,
+    encoding::Encoding,
+    session::{self, make_session_table, SessionState},
     util::print_error,
 };
 
-use super::CommandReturns;
+use super::{Command, CommandReturns};
+
+/// Words after `sessions` which are not session ids or names
+const SUBCOMMANDS: [&str; 7] = [
+    "help",
+    "info",
+    "set-display",
+    "set-encoding",
+    "rename",
+    "tag",
+    "untag",
+];
 
 pub struct Sessions {}
 
@@ -34,37 +48,34 @@ impl super::Command for Sessions {
             return CommandReturns::new(true, args.manager);
         }
 
+        match args.args[0].as_str() {
+            "info" => return info(args),
+            "set-display" => return set_display(args).await,
+            "set-encoding" => return set_encoding(args).await,
+            "rename" => return rename(args).await,
+            "tag" | "untag" => return tag(args).await,
+            "-k" | "-K" | "--prune-dead" => return kill(args).await,
+            _ => {}
+        }
+
         // print help message
-        if args.args.len() == 1 && args.args[0] == "help" || args.args.len() > 2 {
+        if args.args.len() == 1 && args.args[0] == "help" || args.args.len() > 1 {
             Self::help();
-            return CommandReturns::new(true, args.manager);
+            return CommandReturns::new(args.args[0] == "help", args.manager);
         }
 
         // change shell local to remote
-        let id = match args.args[0].parse::<u16>() {
-            Ok(num) => num,
+        let id = match session::resolve_session(&args.args[0]) {
+            Ok(id) => id,
             Err(e) => {
-                print_error("failed to parse an arg as port", anyhow!(e.to_string()));
-                Self::help();
+                print_error("failed to find the session", e);
                 return CommandReturns::new(false, args.manager);
             }
         };
-        if match session::is_session_exist(id) {
-            Ok(b) => b,
-            Err(e) => {
-                print_error("failed to check if session exists", e);
-                return CommandReturns::new(false, args.manager);
-            }
-        } {
-            let mut manager = args.manager;
-            manager.current_session_id = Some(id);
-            manager.is_shell_remote = true;
-            return CommandReturns::new(true, manager);
-        } else {
-            println!("Session {} not found", id);
-        }
-
-        CommandReturns::new(true, args.manager)
+        let mut manager = args.manager;
+        manager.current_session_id = Some(id);
+        manager.is_shell_remote = true;
+        CommandReturns::new(true, manager)
     }
 
     fn help() {
@@ -77,9 +88,300 @@ impl super::Command for Sessions {
         println!(
             "\t{}",
             tidy_usage(
-                "sessions <id>",
-                "Switch current shell context to a remote session with the given id"
+                "sessions <id|name>",
+                "Switch current shell context to a remote session with the given id or name"
+            )
+        );
+        println!(
+            "\t{}",
+            tidy_usage(
+                "sessions info <id|name>",
+                "Show the host, user and connection of a session"
+            )
+        );
+        println!(
+            "\t{}",
+            tidy_usage(
+                "sessions set-display <id|name> <mode>",
+                "Show the output of the session as lossy (utf-8, default), hexdump or escaped"
+            )
+        );
+        println!(
+            "\t{}",
+            tidy_usage(
+                "sessions set-encoding <id|name> <encoding>",
+                "Override the detected encoding of the session: utf-8, cp932, euc-jp or utf-16le"
+            )
+        );
+        println!(
+            "\t{}",
+            tidy_usage(
+                "sessions set-encoding <id|name> [-o <encoding>] [-i <encoding>]",
+                "Override only the encoding of the output or the input of the session"
+            )
+        );
+        println!(
+            "\t{}",
+            tidy_usage(
+                "sessions rename <id|name> <name>",
+                "Name a session so that it can be referred to by the name"
+            )
+        );
+        println!(
+            "\t{}",
+            tidy_usage(
+                "sessions tag <id|name> <tag>...",
+                "Add free-form labels to a session"
+            )
+        );
+        println!(
+            "\t{}",
+            tidy_usage(
+                "sessions untag <id|name> <tag>...",
+                "Remove labels from a session"
+            )
+        );
+        println!(
+            "\t{}",
+            tidy_usage(
+                "sessions -k <id|name> [--exit]",
+                "Close a session, sending `exit` to the shell first with --exit"
+            )
+        );
+        println!(
+            "\t{}",
+            tidy_usage("sessions -K [--exit]", "Close all sessions")
+        );
+        println!(
+            "\t{}",
+            tidy_usage(
+                "sessions --prune-dead [--exit]",
+                "Close the sessions whose connection is lost"
             )
         );
     }
 }
+
+fn info(args: super::CommandArgs) -> CommandReturns {
+    if args.args.len() != 2 {
+        Sessions::help();
+        return CommandReturns::new(false, args.manager);
+    }
+
+    let metadata = match session::resolve_session(&args.args[1]).and_then(session::get_metadata) {
+        Ok(m) => m,
+        Err(e) => {
+            print_error("failed to find the session", e);
+            return CommandReturns::new(false, args.manager);
+        }
+    };
+    let entries = metadata.entries();
+    let width = entries.iter().map(|(k, _)| k.len()).max().unwrap_or(0);
+    for (key, value) in entries {
+        println!("  {:<width$}  {}", key, value, width = width);
+    }
+
+    CommandReturns::new(true, args.manager)
+}
+
+async fn set_display(args: super::CommandArgs) -> CommandReturns {
+    if args.args.len() != 3 {
+        Sessions::help();
+        return CommandReturns::new(false, args.manager);
+    }
+
+    let id = match session::resolve_session(&args.args[1]) {
+        Ok(id) => id,
+        Err(e) => {
+            print_error("failed to find the session", e);
+            return CommandReturns::new(false, args.manager);
+        }
+    };
+    let display = match args.args[2].parse::<DisplayMode>() {
+        Ok(d) => d,
+        Err(e) => {
+            print_error("failed to parse an arg as display mode", e);
+            return CommandReturns::new(false, args.manager);
+        }
+    };
+
+    if let Err(e) = session::set_display(id, display).await {
+        print_error("failed to change the display mode", e);
+        return CommandReturns::new(false, args.manager);
+    }
+
+    CommandReturns::new(true, args.manager)
+}
+
+/// `sessions set-encoding <id|name> <encoding>` sets both of the output and the input,
+/// `-o` and `-i` set one of them
+async fn set_encoding(args: super::CommandArgs) -> CommandReturns {
+    if args.args.len() < 3 {
+        Sessions::help();
+        return CommandReturns::new(false, args.manager);
+    }
+
+    let id = match session::resolve_session(&args.args[1]) {
+        Ok(id) => id,
+        Err(e) => {
+            print_error("failed to find the session", e);
+            return CommandReturns::new(false, args.manager);
+        }
+    };
+    let (mut output, mut input) = (None, None);
+    match &args.args[2..] {
+        [encoding] => {
+            output = Some(encoding);
+            input = Some(encoding);
+        }
+        rest => {
+            for pair in rest.chunks(2) {
+                match pair {
+                    [flag, encoding] if flag == "-o" => output = Some(encoding),
+                    [flag, encoding] if flag == "-i" => input = Some(encoding),
+                    _ => {
+                        Sessions::help();
+                        return CommandReturns::new(false, args.manager);
+                    }
+                }
+            }
+        }
+    }
+    let parse = |arg: Option<&String>| arg.map(|a| a.parse::<Encoding>()).transpose();
+    let (output, input) = match parse(output).and_then(|o| Ok((o, parse(input)?))) {
+        Ok(e) => e,
+        Err(e) => {
+            print_error("failed to parse an arg as encoding", e);
+            return CommandReturns::new(false, args.manager);
+        }
+    };
+
+    if let Err(e) = session::set_encoding(id, output, input).await {
+        print_error("failed to change the encoding", e);
+        return CommandReturns::new(false, args.manager);
+    }
+
+    CommandReturns::new(true, args.manager)
+}
+
+async fn rename(args: super::CommandArgs) -> CommandReturns {
+    if args.args.len() != 3 {
+        Sessions::help();
+        return CommandReturns::new(false, args.manager);
+    }
+
+    let id = match session::resolve_session(&args.args[1]) {
+        Ok(id) => id,
+        Err(e) => {
+            print_error("failed to find the session", e);
+            return CommandReturns::new(false, args.manager);
+        }
+    };
+    // `sessions <name>` must not be taken for a subcommand
+    let name = &args.args[2];
+    if SUBCOMMANDS.contains(&name.as_str()) {
+        print_error(
+            "failed to rename the session",
+            anyhow!("\"{}\" is reserved for a subcommand", name),
+        );
+        return CommandReturns::new(false, args.manager);
+    }
+
+    if let Err(e) = session::rename(id, name).await {
+        print_error("failed to rename the session", e);
+        return CommandReturns::new(false, args.manager);
+    }
+
+    CommandReturns::new(true, args.manager)
+}
+
+/// `sessions tag` and `sessions untag`
+async fn tag(args: super::CommandArgs) -> CommandReturns {
+    if args.args.len() < 3 {
+        Sessions::help();
+        return CommandReturns::new(false, args.manager);
+    }
+
+    let id = match session::resolve_session(&args.args[1]) {
+        Ok(id) => id,
+        Err(e) => {
+            print_error("failed to find the session", e);
+            return CommandReturns::new(false, args.manager);
+        }
+    };
+    let tags = args.args[2..].to_vec();
+
+    let result = if args.args[0] == "tag" {
+        session::tag(id, tags).await
+    } else {
+        session::untag(id, tags).await
+    };
+    if let Err(e) = result {
+        print_error("failed to change the tags", e);
+        return CommandReturns::new(false, args.manager);
+    }
+
+    CommandReturns::new(true, args.manager)
+}
+
+/// `sessions -k`, `sessions -K` and `sessions --prune-dead`
+async fn kill(args: super::CommandArgs) -> CommandReturns {
+    let mut rest = args.args[1..].to_vec();
+    let polite = match rest.iter().position(|a| a == "--exit") {
+        Some(i) => {
+            rest.remove(i);
+            true
+        }
+        None => false,
+    };
+
+    let closed = match (args.args[0].as_str(), rest.as_slice()) {
+        ("-k", [target]) => {
+            let id = match session::resolve_session(target) {
+                Ok(id) => id,
+                Err(e) => {
+                    print_error("failed to find the session", e);
+                    return CommandReturns::new(false, args.manager);
+                }
+            };
+            if let Err(e) = session::close(id, polite).await {
+                // the session is dropped anyway
+                print_error(&format!("failed to close session {} cleanly", id), e);
+            }
+            vec![id]
+        }
+        ("-K", []) => match session::close_matching(polite, |_| true).await {
+            Ok(ids) => ids,
+            Err(e) => {
+                print_error("failed to close the sessions", e);
+                return CommandReturns::new(false, args.manager);
+            }
+        },
+        ("--prune-dead", []) => {
+            match session::close_matching(polite, |m| m.state == SessionState::Dead).await {
+                Ok(ids) => ids,
+                Err(e) => {
+                    print_error("failed to close the sessions", e);
+                    return CommandReturns::new(false, args.manager);
+                }
+            }
+        }
+        _ => {
+            Sessions::help();
+            return CommandReturns::new(false, args.manager);
+        }
+    };
+
+    for id in &closed {
+        info!("session {} closed", id);
+    }
+    let mut manager = args.manager;
+    if manager
+        .current_session_id
+        .is_some_and(|id| closed.contains(&id))
+    {
+        manager.current_session_id = None;
+        manager.is_shell_remote = false;
+    }
+    CommandReturns::new(true, manager)
+}
@@ -0,0 +1,54 @@
+use crate::{
+    config,
+    util::{print_error, tidy_usage},
+};
+
+use super::CommandReturns;
+
+pub struct Set {}
+
+impl super::Command for Set {
+    fn name() -> String {
+        "set".to_string()
+    }
+
+    fn info() -> String {
+        "Show or change settings".to_string()
+    }
+
+    async fn exec(args: super::CommandArgs) -> super::CommandReturns {
+        // print all settings
+        if args.args.is_empty() {
+            let entries = match config::entries() {
+                Ok(e) => e,
+                Err(e) => {
+                    print_error("failed to get settings", e);
+                    return CommandReturns::new(false, args.manager);
+                }
+            };
+            let width = entries.iter().map(|(k, _)| k.len()).max().unwrap_or(0);
+            for (key, value) in entries {
+                println!("  {:<width$}  {}", key, value, width = width);
+            }
+            return CommandReturns::new(true, args.manager);
+        }
+
+        if args.args.len() != 2 {
+            Self::help();
+            return CommandReturns::new(args.args[0] == "help", args.manager);
+        }
+
+        if let Err(e) = config::set(&args.args[0], &args.args[1]) {
+            print_error("failed to change the setting", e);
+            return CommandReturns::new(false, args.manager);
+        }
+
+        CommandReturns::new(true, args.manager)
+    }
+
+    fn help() {
+        println!("Usage:");
+        println!("\t{}", tidy_usage("set", "Show all settings"));
+        println!("\t{}", tidy_usage("set <key> <value>", "Change a setting"));
+    }
+}
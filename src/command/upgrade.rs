@@ -0,0 +1,70 @@
+use log::info;
+
+use crate::{
+    session,
+    util::{print_error, tidy_usage},
+};
+
+use super::CommandReturns;
+
+pub struct Upgrade {}
+
+impl super::Command for Upgrade {
+    fn name() -> String {
+        "upgrade".to_string()
+    }
+
+    fn info() -> String {
+        "Upgrade a session to a pty and interact with it".to_string()
+    }
+
+    async fn exec(args: super::CommandArgs) -> super::CommandReturns {
+        if args.args.len() > 1 || args.args.first().is_some_and(|a| a == "help") {
+            Self::help();
+            return CommandReturns::new(args.args.len() == 1, args.manager);
+        }
+
+        let id = match args.args.first() {
+            Some(target) => match session::resolve_session(target) {
+                Ok(id) => id,
+                Err(e) => {
+                    print_error("failed to find the session", e);
+                    return CommandReturns::new(false, args.manager);
+                }
+            },
+            None => match args.manager.current_session_id {
+                Some(id) => id,
+                None => {
+                    Self::help();
+                    return CommandReturns::new(false, args.manager);
+                }
+            },
+        };
+
+        if let Err(e) = session::upgrade(id).await {
+            print_error(&format!("failed to upgrade session {}", id), e);
+            return CommandReturns::new(false, args.manager);
+        }
+
+        info!("raw mode: press Ctrl-] to return to sayo");
+        if let Err(e) = session::interact(id).await {
+            println!();
+            print_error(&format!("failed to interact with session {}", id), e);
+            return CommandReturns::new(false, args.manager);
+        }
+        println!();
+
+        CommandReturns::new(true, args.manager)
+    }
+
+    fn help() {
+        info!("usage:");
+        println!(
+            "  {}",
+            tidy_usage(
+                "upgrade [<id|name>]",
+                "Upgrade a session (default: current) to a pty and interact with it in raw mode. Ctrl-] returns to sayo"
+            )
+        );
+    }
+}
@@ -0,0 +1,144 @@
+use std::{sync::Mutex, time::Duration};
+
+use anyhow::{anyhow, Context, Result};
+
+static CONFIG: once_cell::sync::Lazy<Mutex<Config>> =
+    once_cell::sync::Lazy::new(|| Mutex::new(Config::default()));
+
+/// Settings which can be changed with the `set` command
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// how long to wait for the first response of a new shell
+    pub handshake_banner_timeout: Duration,
+    /// how long to wait for the output of `whoami` while initializing a session
+    pub handshake_whoami_timeout: Duration,
+    /// how long to wait for the shell type, os and architecture of a new shell
+    pub handshake_fingerprint_timeout: Duration,
+    /// how long to wait for the hostname, kernel, ids and so on of a new shell
+    pub handshake_host_timeout: Duration,
+    /// how long a command run from the prompt may take before it is killed (None: no limit)
+    pub command_timeout: Option<Duration>,
+    /// how often an idle session is checked by running a no-op command (None: never)
+    pub session_probe_interval: Option<Duration>,
+    /// how long a probe may take before the session is considered dead
+    pub session_probe_timeout: Duration,
+    /// whether dead sessions are removed, instead of being kept in the list for review
+    pub session_remove_dead: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            handshake_banner_timeout: Duration::from_secs(5),
+            handshake_whoami_timeout: Duration::from_secs(10),
+            handshake_fingerprint_timeout: Duration::from_secs(10),
+            handshake_host_timeout: Duration::from_secs(10),
+            command_timeout: None,
+            session_probe_interval: None,
+            session_probe_timeout: Duration::from_secs(10),
+            session_remove_dead: false,
+        }
+    }
+}
+
+pub fn get() -> Result<Config> {
+    match CONFIG.lock() {
+        Ok(c) => Ok(c.clone()),
+        Err(e) => Err(anyhow!(e.to_string())),
+    }
+}
+
+/// List of (key, value) of all settings
+pub fn entries() -> Result<Vec<(&'static str, String)>> {
+    let config = get()?;
+    Ok(vec![
+        (
+            "handshake.banner-timeout",
+            format_secs(config.handshake_banner_timeout),
+        ),
+        (
+            "handshake.whoami-timeout",
+            format_secs(config.handshake_whoami_timeout),
+        ),
+        (
+            "handshake.fingerprint-timeout",
+            format_secs(config.handshake_fingerprint_timeout),
+        ),
+        (
+            "handshake.host-timeout",
+            format_secs(config.handshake_host_timeout),
+        ),
+        (
+            "command.timeout",
+            format_optional_secs(config.command_timeout),
+        ),
+        (
+            "session.probe-interval",
+            format_optional_secs(config.session_probe_interval),
+        ),
+        (
+            "session.probe-timeout",
+            format_secs(config.session_probe_timeout),
+        ),
+        (
+            "session.remove-dead",
+            config.session_remove_dead.to_string(),
+        ),
+    ])
+}
+
+pub fn set(key: &str, value: &str) -> Result<()> {
+    let mut config = match CONFIG.lock() {
+        Ok(c) => c,
+        Err(e) => return Err(anyhow!(e.to_string())),
+    };
+    match key {
+        "handshake.banner-timeout" => config.handshake_banner_timeout = parse_secs(value)?,
+        "handshake.whoami-timeout" => config.handshake_whoami_timeout = parse_secs(value)?,
+        "handshake.fingerprint-timeout" => {
+            config.handshake_fingerprint_timeout = parse_secs(value)?
+        }
+        "handshake.host-timeout" => config.handshake_host_timeout = parse_secs(value)?,
+        "command.timeout" => config.command_timeout = parse_optional_secs(value)?,
+        "session.probe-interval" => config.session_probe_interval = parse_optional_secs(value)?,
+        "session.probe-timeout" => config.session_probe_timeout = parse_secs(value)?,
+        "session.remove-dead" => config.session_remove_dead = parse_bool(value)?,
+        _ => return Err(anyhow!("unknown key: {}", key)),
+    }
+    Ok(())
+}
+
+fn parse_secs(value: &str) -> Result<Duration> {
+    let secs = value
+        .parse::<f64>()
+        .with_context(|| format!("\"{}\" is not a number of seconds", value))?;
+    Duration::try_from_secs_f64(secs).with_context(|| format!("\"{}\" is out of range", value))
+}
+
+/// "none" or 0 means no limit
+fn parse_optional_secs(value: &str) -> Result<Option<Duration>> {
+    if value == "none" {
+        return Ok(None);
+    }
+    let duration = parse_secs(value)?;
+    Ok((!duration.is_zero()).then_some(duration))
+}
+
+fn parse_bool(value: &str) -> Result<bool> {
+    match value {
+        "true" | "on" | "yes" => Ok(true),
+        "false" | "off" | "no" => Ok(false),
+        _ => Err(anyhow!("\"{}\" is neither true nor false", value)),
+    }
+}
+
+fn format_secs(duration: Duration) -> String {
+    format!("{}s", duration.as_secs_f64())
+}
+
+fn format_optional_secs(duration: Option<Duration>) -> String {
+    match duration {
+        Some(d) => format_secs(d),
+        None => "none".to_string(),
+    }
+}
@@ -0,0 +1,174 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::{anyhow, Error};
+
+use crate::encoding::{Decoder, Encoding};
+
+/// Bytes in a row of the hexdump
+const HEXDUMP_WIDTH: usize = 16;
+
+/// How the output of a command is shown
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DisplayMode {
+    /// decoded from the encoding of the session, with invalid bytes replaced by U+FFFD
+    #[default]
+    Lossy,
+    /// offsets, hex and ascii like `xxd`
+    Hexdump,
+    /// printable ascii as is and everything else as `\xNN`
+    Escaped,
+}
+
+impl DisplayMode {
+    pub const ALL: [DisplayMode; 3] = [Self::Lossy, Self::Hexdump, Self::Escaped];
+}
+
+impl fmt::Display for DisplayMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Lossy => "lossy",
+            Self::Hexdump => "hexdump",
+            Self::Escaped => "escaped",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for DisplayMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|m| m.to_string() == s)
+            .ok_or_else(|| {
+                anyhow!(
+                    "unknown display mode: {} (expected one of {})",
+                    s,
+                    Self::ALL.map(|m| m.to_string()).join(", ")
+                )
+            })
+    }
+}
+
+/// Print received lines in a display mode.
+/// Only the lossy mode decodes the bytes, and the others show them as they were received.
+/// The hexdump is printed in rows of fixed width, so bytes are held until a row is filled.
+pub struct Renderer {
+    mode: DisplayMode,
+    /// None for utf-8, which is printed as it is
+    decoder: Option<Decoder>,
+    /// newline as the shell writes it
+    newline: Vec<u8>,
+    /// offset of the first byte in `row`
+    offset: usize,
+    row: Vec<u8>,
+}
+
+impl Renderer {
+    pub fn new(mode: DisplayMode, encoding: Encoding) -> Self {
+        Self {
+            mode,
+            decoder: encoding.decoder(),
+            newline: encoding.ascii(b"\n"),
+            offset: 0,
+            row: vec![],
+        }
+    }
+
+    /// Print a line. The newline at the end, if any, is part of it.
+    pub fn line(&mut self, line: &[u8]) {
+        match self.mode {
+            DisplayMode::Lossy => {
+                let line = match &mut self.decoder {
+                    Some(decoder) => decoder.decode(line),
+                    None => String::from_utf8_lossy(line).into_owned(),
+                };
+                println!("{}", line.strip_suffix('\n').unwrap_or(&line));
+            }
+            DisplayMode::Escaped => {
+                let line = line.strip_suffix(self.newline.as_slice()).unwrap_or(line);
+                println!("{}", escape(line));
+            }
+            DisplayMode::Hexdump => {
+                self.row.extend_from_slice(line);
+                while self.row.len() >= HEXDUMP_WIDTH {
+                    let rest = self.row.split_off(HEXDUMP_WIDTH);
+                    self.print_row();
+                    self.row = rest;
+                }
+            }
+        }
+    }
+
+    /// Print bytes which are not split into lines, all at once
+    pub fn text(&mut self, mut text: &[u8]) {
+        while !text.is_empty() {
+            let end = text
+                .windows(self.newline.len())
+                .position(|w| w == self.newline)
+                .map_or(text.len(), |i| i + self.newline.len());
+            let (line, rest) = text.split_at(end);
+            self.line(line);
+            text = rest;
+        }
+        self.finish();
+    }
+
+    /// Print the bytes held back for the last row
+    pub fn finish(&mut self) {
+        if !self.row.is_empty() {
+            self.print_row();
+            self.row.clear();
+        }
+    }
+
+    fn print_row(&mut self) {
+        let hex = self
+            .row
+            .chunks(2)
+            .map(|pair| {
+                pair.iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect::<String>()
+            })
+            .collect::<Vec<String>>()
+            .join(" ");
+        let ascii = self
+            .row
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect::<String>();
+        // 2 hex digits for every byte and a space between every 2 bytes
+        let width = HEXDUMP_WIDTH * 2 + HEXDUMP_WIDTH / 2 - 1;
+        println!(
+            "{:08x}: {:<width$}  {}",
+            self.offset,
+            hex,
+            ascii,
+            width = width
+        );
+        self.offset += self.row.len();
+    }
+}
+
+/// Show printable ascii as is and the other bytes as escape sequences
+fn escape(bytes: &[u8]) -> String {
+    let mut escaped = String::new();
+    for &b in bytes {
+        match b {
+            b'\\' => escaped.push_str("\\\\"),
+            b'\t' => escaped.push_str("\\t"),
+            b'\r' => escaped.push_str("\\r"),
+            b' '..=b'~' => escaped.push(b as char),
+            _ => escaped.push_str(&format!("\\x{:02x}", b)),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,166 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::{anyhow, Error, Result};
+
+/// Character encoding which a shell reads and writes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Encoding {
+    #[default]
+    Utf8,
+    /// Shift_JIS with the microsoft extensions, used by japanese windows
+    Cp932,
+    EucJp,
+    /// written by powershell and `cmd /u` when the output goes to a pipe.
+    /// They still read commands in the code page of the console,
+    /// so a session usually has another encoding for the input
+    Utf16Le,
+}
+
+impl Encoding {
+    pub const ALL: [Encoding; 4] = [Self::Utf8, Self::Cp932, Self::EucJp, Self::Utf16Le];
+
+    /// From the output of `locale charmap`
+    pub fn from_charmap(charmap: &str) -> Option<Self> {
+        match charmap.to_ascii_uppercase().as_str() {
+            "UTF-8" | "UTF8" => Some(Self::Utf8),
+            "SHIFT_JIS" | "SJIS" | "CP932" | "WINDOWS-31J" => Some(Self::Cp932),
+            "EUC-JP" | "EUCJP" => Some(Self::EucJp),
+            _ => None,
+        }
+    }
+
+    /// From a windows code page
+    pub fn from_code_page(code_page: u32) -> Option<Self> {
+        match code_page {
+            65001 => Some(Self::Utf8),
+            932 => Some(Self::Cp932),
+            20932 | 51932 => Some(Self::EucJp),
+            1200 => Some(Self::Utf16Le),
+            _ => None,
+        }
+    }
+
+    fn codec(&self) -> &'static encoding_rs::Encoding {
+        match self {
+            Self::Utf8 => encoding_rs::UTF_8,
+            Self::Cp932 => encoding_rs::SHIFT_JIS,
+            Self::EucJp => encoding_rs::EUC_JP,
+            Self::Utf16Le => encoding_rs::UTF_16LE,
+        }
+    }
+
+    /// Make a decoder which turns received bytes into utf-8,
+    /// or None if they are passed through as they are
+    pub fn decoder(&self) -> Option<Decoder> {
+        if *self == Self::Utf8 {
+            return None;
+        }
+        Some(Decoder {
+            decoder: self.codec().new_decoder_without_bom_handling(),
+        })
+    }
+
+    /// Decode received bytes, or None if they are not valid in the encoding
+    pub fn decode(&self, data: &[u8]) -> Option<String> {
+        self.codec()
+            .decode_without_bom_handling_and_without_replacement(data)
+            .map(|d| d.into_owned())
+    }
+
+    /// Decode received bytes, replacing invalid ones by U+FFFD
+    pub fn decode_lossy(&self, data: &[u8]) -> String {
+        self.codec()
+            .decode_without_bom_handling(data)
+            .0
+            .into_owned()
+    }
+
+    /// Ascii text as the shell writes it, to find markers and newlines in the received bytes.
+    /// The other encodings are supersets of ascii.
+    pub fn ascii(&self, text: &[u8]) -> Vec<u8> {
+        match self {
+            Self::Utf16Le => text.iter().flat_map(|&b| [b, 0]).collect(),
+            Self::Utf8 | Self::Cp932 | Self::EucJp => text.to_vec(),
+        }
+    }
+
+    /// Encode bytes to send. Bytes which are not utf-8, such as raw key input cut in the
+    /// middle of a character, are sent as they are.
+    pub fn encode(&self, data: &[u8]) -> Result<Vec<u8>> {
+        if *self == Self::Utf8 {
+            return Ok(data.to_vec());
+        }
+        let text = match std::str::from_utf8(data) {
+            Ok(t) => t,
+            Err(_) => return Ok(data.to_vec()),
+        };
+        // encoding_rs only decodes utf-16
+        if *self == Self::Utf16Le {
+            return Ok(text.encode_utf16().flat_map(u16::to_le_bytes).collect());
+        }
+        let encoding = self.codec();
+        let (encoded, _, unmappable) = encoding.encode(text);
+        if unmappable {
+            let characters = text
+                .chars()
+                .filter(|c| encoding.encode(c.encode_utf8(&mut [0; 4])).2)
+                .collect::<String>();
+            return Err(anyhow!(
+                "\"{}\" can not be encoded in {}",
+                characters.escape_debug(),
+                self
+            ));
+        }
+        Ok(encoded.into_owned())
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Utf8 => "utf-8",
+            Self::Cp932 => "cp932",
+            Self::EucJp => "euc-jp",
+            Self::Utf16Le => "utf-16le",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "utf-8" | "utf8" => Ok(Self::Utf8),
+            "cp932" | "shift_jis" | "sjis" => Ok(Self::Cp932),
+            "euc-jp" | "eucjp" => Ok(Self::EucJp),
+            "utf-16le" | "utf16le" => Ok(Self::Utf16Le),
+            _ => Err(anyhow!(
+                "unknown encoding: {} (expected one of {})",
+                s,
+                Self::ALL.map(|e| e.to_string()).join(", ")
+            )),
+        }
+    }
+}
+
+/// Turns a stream of received bytes into utf-8.
+/// Characters cut between two chunks are completed by the next chunk.
+pub struct Decoder {
+    decoder: encoding_rs::Decoder,
+}
+
+impl Decoder {
+    /// Decode bytes. Invalid bytes are replaced by U+FFFD.
+    pub fn decode(&mut self, input: &[u8]) -> String {
+        let mut decoded = String::with_capacity(
+            self.decoder
+                .max_utf8_buffer_length(input.len())
+                .unwrap_or(input.len() * 3),
+        );
+        // the buffer is large enough, so the whole input is decoded at once
+        let _ = self.decoder.decode_to_string(input, &mut decoded, false);
+        decoded
+    }
+}
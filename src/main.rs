@@ -1,12 +1,25 @@
+mod access;
 mod command;
+mod config;
+mod display;
+mod encoding;
+mod frame;
+mod http;
+mod listener;
 mod session;
+mod shell;
+mod terminal;
+mod tls;
+mod transport;
 mod util;
 
 use anyhow::anyhow;
 use command::{CommandArgs, CommandReturns};
-use log::Level;
+use display::Renderer;
+use log::{info, warn, Level};
 use std::io::Write;
 use std::process;
+use tokio_util::sync::CancellationToken;
 use util::print_error;
 
 use crate::util::color;
@@ -40,11 +53,37 @@ async fn main() {
                     continue 'main_loop;
                 }
             };
+            // output which arrived while we were away or at the prompt
+            match session::take_unread(session_id).await {
+                Ok(output) if !output.is_empty() => {
+                    info!(
+                        "output of session {} while no command was running:",
+                        session_id
+                    );
+                    Renderer::new(session_metadata.display, session_metadata.encoding)
+                        .text(&output);
+                }
+                Ok(_) => {}
+                Err(e) => print_error("failed to get the output of the session", e),
+            }
+            // a dead session is kept only to be looked at, so go back to the local shell
+            if session_metadata.state == session::SessionState::Dead {
+                warn!("session {} is dead", session_id);
+                manager.is_shell_remote = false;
+                continue 'main_loop;
+            }
 
-            let prompt = format!(
-                "{} ",
-                color::blue(&format!("[sayo][{}]>", session_metadata.cwd))
-            );
+            let prompt = match session_metadata.last_exit_status {
+                Some(status) if status != 0 => format!(
+                    "{}{} ",
+                    color::blue(&format!("[sayo][{}]", session_metadata.cwd)),
+                    color::red(&format!("[{}]>", status))
+                ),
+                _ => format!(
+                    "{} ",
+                    color::blue(&format!("[sayo][{}]>", session_metadata.cwd))
+                ),
+            };
 
             let readline = rl.readline(&prompt);
 
@@ -74,9 +113,24 @@ async fn main() {
                 continue;
             }
 
-            if let Err(e) = session::execute_command_prettily(session_id, line.as_bytes()).await {
+            let timeout = match config::get() {
+                Ok(c) => c.command_timeout,
+                Err(e) => {
+                    print_error("failed to get settings", e);
+                    continue;
+                }
+            };
+            // the session stays usable after a timeout, so go back to the prompt
+            if let Err(e) = session::execute_command_prettily(
+                session_id,
+                line.as_bytes(),
+                session_metadata.display,
+                timeout,
+                &CancellationToken::new(),
+            )
+            .await
+            {
                 print_error("failed to execute command", e);
-                break;
             }
         } else {
             let prompt = format!("{} ", color::red("[sayo]>"));
@@ -99,7 +153,7 @@ async fn main() {
             let input = line.split_whitespace().collect::<Vec<&str>>();
 
             // Skip when input is empty
-            if input.first().is_none() {
+            if input.is_empty() {
                 continue;
             }
 
@@ -0,0 +1,31 @@
+# Run a shell on a pseudo terminal and relay it to stdin/stdout.
+# Unlike pty.spawn, "\x1b]sayo;<rows>;<cols>\x07" in the input resizes the terminal.
+# usage: python -c '<this script>' <shell>
+import fcntl, os, pty, re, select, struct, sys, termios
+
+pid, fd = pty.fork()
+if pid == 0:
+    os.execlp(sys.argv[1], sys.argv[1])
+
+resize = re.compile(br"\x1b\]sayo;(\d+);(\d+)\x07")
+while True:
+    readable = select.select([0, fd], [], [])[0]
+    if fd in readable:
+        try:
+            data = os.read(fd, 4096)
+        except OSError:
+            break
+        if not data:
+            break
+        os.write(1, data)
+    if 0 in readable:
+        data = os.read(0, 4096)
+        if not data:
+            break
+        for rows, cols in resize.findall(data):
+            winsize = struct.pack("HHHH", int(rows), int(cols), 0, 0)
+            fcntl.ioctl(fd, termios.TIOCSWINSZ, winsize)
+        data = resize.sub(b"", data)
+        if data:
+            os.write(fd, data)
+os.waitpid(pid, 0)
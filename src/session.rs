@@ -1,437 +1,2110 @@
 use std::{
-    io::{Read, Write},
-    net::{Ipv4Addr, SocketAddr},
+    collections::VecDeque,
+    net::SocketAddr,
     sync::Mutex,
+    time::{Duration, Instant},
 };
 
 use anyhow::{anyhow, Context, Result};
-use log::{error, info};
+use log::{info, warn};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf},
+    io::AsyncWriteExt,
     net::{TcpListener, TcpStream},
+    signal::unix::{signal, SignalKind},
+    sync::{mpsc, oneshot, watch},
+    time::{sleep, timeout},
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    access::{self, AccessRules},
+    config,
+    display::DisplayMode,
+    encoding::Encoding,
+    frame::{self, Frame, FrameMode, FrameStatus, Syntax},
+    shell::{self, HostInfo, PtySpawner, ShellType},
+    terminal::{self, KeyCapture, RawMode, StdinReader, TermSize},
+    tls::TlsIdentity,
+    transport::{LinePrinter, Socket, TimedOut},
+    util::format_duration,
 };
 
 // このモジュール以外から直接アクセスできないようにして、デッドロックを防止する
-static SESSIONS_ARRAY: once_cell::sync::Lazy<Mutex<Vec<Session>>> =
+// セッション本体はそれぞれのタスクが持ち、ここにはハンドルだけを置く
+static SESSIONS_ARRAY: once_cell::sync::Lazy<Mutex<Vec<SessionHandle>>> =
     once_cell::sync::Lazy::new(|| Mutex::new(vec![]));
 
-static LARGEST_SESSION_ID: once_cell::sync::Lazy<Mutex<u16>> =
+static NEXT_SESSION_ID: once_cell::sync::Lazy<Mutex<u16>> =
     once_cell::sync::Lazy::new(|| Mutex::new(0));
 
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const PTY_UPGRADE_TIMEOUT: Duration = Duration::from_secs(10);
+const PTY_SPAWN_WAIT: Duration = Duration::from_secs(2);
+const RESYNC_TIMEOUT: Duration = Duration::from_secs(10);
+const RESYNC_RETRY_INTERVAL: Duration = Duration::from_secs(2);
+/// time given to a command in a pty to die by Ctrl-C before it is killed harder
+const INTERRUPT_WAIT: Duration = Duration::from_secs(2);
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+/// time given to the posix probe before probes in the other syntaxes are sent
+const POSIX_PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+/// output kept while no command is running
+const SCROLLBACK_LIMIT: usize = 64 * 1024;
+/// time given to the shell to print its prompt when it is learned
+const PROMPT_TIMEOUT: Duration = Duration::from_secs(5);
+/// bytes at the start of a frame line which tell where its echo starts.
+/// Long enough not to be in a prompt, and short enough not to be wrapped by the terminal
+const ECHO_PREFIX_LEN: usize = 16;
+/// requests which can wait while a session is busy
+const REQUEST_QUEUE_SIZE: usize = 16;
+/// characters of the tls fingerprint shown in the session table, i.e. the first 4 bytes
+const TLS_FINGERPRINT_PREFIX_LEN: usize = 11;
+
 #[derive(Debug)]
-pub struct Socket {
-    address: SocketAddr,
-    reader: ReadHalf<TcpStream>,
-    writer: WriteHalf<TcpStream>,
-}
-
-impl Socket {
-    async fn new(port: u16) -> Result<Self> {
-        let address = SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), port);
-        let listener = TcpListener::bind(address).await?;
-        let (socket, remote_address) = listener.accept().await?;
-        let (reader, writer) = tokio::io::split(socket);
-        Ok(Self {
-            address: remote_address,
-            reader,
-            writer,
-        })
-    }
+pub struct Session {
+    pub metadata: SessionMetadata,
+    pub socket: Socket,
+    scrollback: Scrollback,
+    /// what the shell prints after every command, None until it is learned
+    prompt: Option<Vec<u8>>,
+    /// false after a command is interrupted, until the shell is resynchronized.
+    /// Bytes skipped in between are leftovers of the command, not output for the scrollback
+    in_sync: bool,
+}
 
-    async fn send(&mut self, data: &[u8]) -> Result<()> {
-        self.writer.write_all(data.to_vec().as_slice()).await?;
-        self.writer.flush().await?;
-        Ok(())
+/// Output which arrived while no command was running, such as from background jobs.
+/// The oldest bytes are dropped beyond SCROLLBACK_LIMIT.
+#[derive(Debug, Default)]
+struct Scrollback {
+    buffer: VecDeque<u8>,
+    /// bytes at the end of the buffer which have not been shown
+    unread: usize,
+    /// prompt which the shell is going to print, and how much of it has arrived.
+    /// Only the exact prompt is dropped, and anything else is kept
+    prompt: Vec<u8>,
+    matched: usize,
+}
+
+impl Scrollback {
+    /// Drop the prompt when it arrives next
+    fn expect(&mut self, prompt: &[u8]) {
+        self.forget_prompt();
+        self.prompt = prompt.to_vec();
     }
 
-    async fn sendline(&mut self, data: &[u8]) -> Result<()> {
-        self.send(data).await?;
-        self.send(b"\n").await?;
-        Ok(())
+    /// Stop waiting for the prompt and keep the part which looked like it
+    fn forget_prompt(&mut self) {
+        let matched = self.prompt[..self.matched].to_vec();
+        self.prompt.clear();
+        self.matched = 0;
+        self.append(&matched);
     }
 
-    async fn recvuntil(&mut self, pattern: &[u8]) -> Result<Vec<u8>> {
-        let mut buf = vec![];
-        loop {
-            let mut buf_ = [0];
-            self.reader.read_exact(&mut buf_).await?;
-            buf.extend_from_slice(&buf_[..]);
-            if buf.ends_with(pattern) {
+    fn push(&mut self, mut data: &[u8]) {
+        while self.matched < self.prompt.len() && !data.is_empty() {
+            if data[0] != self.prompt[self.matched] {
+                self.forget_prompt();
                 break;
             }
+            self.matched += 1;
+            data = &data[1..];
         }
-        Ok(buf)
-    }
-
-    async fn printuntil(&mut self, pattern: &[u8], print_pattern: bool) -> Result<()> {
-        let mut buf = vec![];
-        let mut last_line_index = 0;
-        loop {
-            let mut buf_ = [0];
-            self.reader.read_exact(&mut buf_).await?;
-
-            buf.extend_from_slice(&buf_[..]);
-
-            if buf_ == [0x0A] {
-                match String::from_utf8(buf[last_line_index..buf.len()].to_vec()) {
-                    Ok(output) => match output.strip_suffix('\n') {
-                        Some(l) => println!("{}", l),
-                        None => println!("{}", output),
-                    },
-                    Err(e) => {
-                        error!("utf8 error: {}", e.to_string());
-                    }
-                }
-                last_line_index = buf.len();
-            }
-
-            if buf.ends_with(pattern) {
-                if print_pattern {
-                    match String::from_utf8(buf[last_line_index..buf.len()].to_vec()) {
-                        Ok(output) => match output.strip_suffix('\n') {
-                            Some(l) => println!("{}", l),
-                            None => println!("{}", output),
-                        },
-                        Err(e) => {
-                            error!("utf8 error: {}", e.to_string());
-                        }
-                    }
-                } else {
-                    match String::from_utf8(
-                        buf[last_line_index..buf.len() - pattern.len()].to_vec(),
-                    ) {
-                        Ok(output) => match output.strip_suffix('\n') {
-                            Some(l) => println!("{}", l),
-                            None => println!("{}", output),
-                        },
-                        Err(e) => {
-                            error!("utf8 error: {}", e.to_string());
-                        }
-                    }
-                }
-                return Ok(());
-            }
+        if !self.prompt.is_empty() && self.matched == self.prompt.len() {
+            self.prompt.clear();
+            self.matched = 0;
         }
+        self.append(data);
     }
 
-    /// receive until \n and strip \n
-    async fn recvline(&mut self) -> Result<Vec<u8>> {
-        match self.recvuntil(b"\n").await {
-            Ok(l) => Ok(l.strip_suffix(b"\n").unwrap().to_vec()),
-            Err(e) => Err(e),
+    fn append(&mut self, data: &[u8]) {
+        self.buffer.extend(data);
+        self.unread += data.len();
+        if let Some(over) = self.buffer.len().checked_sub(SCROLLBACK_LIMIT) {
+            self.buffer.drain(..over);
+            self.unread = self.unread.min(self.buffer.len());
         }
     }
-}
 
-#[derive(Debug)]
-pub struct Session {
-    pub metadata: SessionMetadata,
-    pub socket: Socket,
+    fn take_unread(&mut self) -> Vec<u8> {
+        let start = self.buffer.len() - self.unread;
+        self.unread = 0;
+        self.buffer.range(start..).copied().collect()
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct SessionMetadata {
     pub id: u16,
+    /// name given by the user, unique among the sessions
+    pub name: Option<String>,
+    /// free-form labels given by the user
+    pub tags: Vec<String>,
     pub username: String,
-    pub address: SocketAddr,
+    /// address of the peer (e.g. "10.10.10.10:4444")
+    pub address: String,
+    pub kind: SessionKind,
+    /// fingerprint of the certificate if the session is encrypted with tls
+    pub tls_fingerprint: Option<String>,
+    /// protocol of the connection (e.g. "tcp", "tls (sniffed)")
+    pub protocol: String,
+    pub cwd: String,
+    /// exit status of the last command
+    pub last_exit_status: Option<i32>,
+    pub shell: ShellType,
+    /// output of `uname -s` or the windows version
+    pub os: Option<String>,
+    pub arch: Option<String>,
+    /// hostname, ids and so on, where the shell could tell them
+    pub host: HostInfo,
+    pub connected_at: Instant,
+    /// program which runs the shell on a pseudo terminal
+    pub pty: Option<PtySpawner>,
+    /// last terminal size pushed to the pseudo terminal
+    pub term_size: Option<TermSize>,
+    /// how the output of commands run from the prompt is shown
+    pub display: DisplayMode,
+    /// character encoding of the output of the shell
+    pub encoding: Encoding,
+    /// character encoding in which the shell reads commands and keys
+    pub input_encoding: Encoding,
+    pub state: SessionState,
+    /// bytes of the scrollback which have not been shown
+    pub unread: usize,
+}
+
+/// Result of a command executed in a session
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub struct CommandOutput {
+    pub stdout: Vec<u8>,
+    /// None if stderr is not captured or the shell can not separate it from stdout
+    pub stderr: Option<Vec<u8>>,
+    pub exit_status: i32,
+    /// from sending the command until the end of its output, including the round trip
+    pub duration: Duration,
+    /// working directory after the command
     pub cwd: String,
 }
 
+impl CommandOutput {
+    pub fn success(&self) -> bool {
+        self.exit_status == 0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionKind {
+    /// reverse shell accepted by a listener bound to the address
+    Reverse(SocketAddr),
+    /// bind shell which we connected to
+    Bind,
+}
+
+impl SessionKind {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Reverse(_) => "reverse",
+            Self::Bind => "bind",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionState {
+    /// waiting for a request
+    Alive,
+    /// running a request such as a command
+    Busy,
+    /// the connection is closed or the shell stopped answering
+    Dead,
+}
+
+impl SessionState {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Alive => "alive",
+            Self::Busy => "busy",
+            Self::Dead => "dead",
+        }
+    }
+}
+
+impl SessionMetadata {
+    /// "<os>/<arch>", or "-" if unknown
+    pub fn platform(&self) -> String {
+        match (&self.os, &self.arch) {
+            (Some(os), Some(arch)) => format!("{}/{}", os, arch),
+            (Some(os), None) => os.clone(),
+            (None, Some(arch)) => arch.clone(),
+            (None, None) => "-".to_string(),
+        }
+    }
+
+    /// List of (key, value) shown by `sessions info`
+    pub fn entries(&self) -> Vec<(&'static str, String)> {
+        let or_unknown = |value: &Option<String>| value.clone().unwrap_or("unknown".to_string());
+        vec![
+            ("id", self.id.to_string()),
+            ("name", self.name.clone().unwrap_or("-".to_string())),
+            ("tags", join_or_dash(&self.tags)),
+            ("state", self.state.name().to_string()),
+            ("address", self.address.clone()),
+            ("kind", self.kind.name().to_string()),
+            (
+                "bind",
+                match self.kind {
+                    SessionKind::Reverse(bind_address) => bind_address.to_string(),
+                    SessionKind::Bind => "-".to_string(),
+                },
+            ),
+            ("protocol", self.protocol.clone()),
+            (
+                "connected",
+                format!("{} ago", format_duration(self.connected_at.elapsed())),
+            ),
+            ("hostname", or_unknown(&self.host.hostname)),
+            ("os", or_unknown(&self.os)),
+            ("distribution", or_unknown(&self.host.distribution)),
+            ("kernel", or_unknown(&self.host.kernel)),
+            ("arch", or_unknown(&self.arch)),
+            ("username", self.username.clone()),
+            ("uid", or_unknown(&self.host.uid)),
+            ("gid", or_unknown(&self.host.gid)),
+            ("groups", join_or_dash(&self.host.groups)),
+            ("shell", self.shell.to_string()),
+            (
+                "pid",
+                self.host
+                    .pid
+                    .map_or("unknown".to_string(), |p| p.to_string()),
+            ),
+            ("cwd", self.cwd.clone()),
+            ("output encoding", self.encoding.to_string()),
+            ("input encoding", self.input_encoding.to_string()),
+            ("display", self.display.to_string()),
+            (
+                "pty",
+                self.pty
+                    .map_or("no", |spawner| spawner.program())
+                    .to_string(),
+            ),
+            (
+                "tls",
+                match &self.tls_fingerprint {
+                    Some(fingerprint) => format!("sha256 {}", fingerprint),
+                    None => "no".to_string(),
+                },
+            ),
+        ]
+    }
+}
+
+/// Comma separated, or "-" if empty
+fn join_or_dash(list: &[String]) -> String {
+    if list.is_empty() {
+        "-".to_string()
+    } else {
+        list.join(",")
+    }
+}
+
 impl Session {
-    pub async fn new(port: u16) -> Result<Self> {
-        let socket = Socket::new(port)
-            .await
-            .context("failed to create new socket")?;
-        let address = socket.address;
+    pub fn new(
+        id: u16,
+        socket: Socket,
+        address: String,
+        kind: SessionKind,
+        tls_fingerprint: Option<String>,
+        protocol: String,
+    ) -> Self {
         let username = "unknown".to_string();
         let cwd = "unknown".to_string();
 
-        info!("connection from: {}", address);
-
-        let largest_session_id = LARGEST_SESSION_ID.lock().unwrap();
-        let id = if *largest_session_id == 0 {
-            0
-        } else {
-            *largest_session_id + 1
-        };
+        match kind {
+            SessionKind::Reverse(_) => info!("connection from: {}", address),
+            SessionKind::Bind => info!("connected to: {}", address),
+        }
 
         let metadata = SessionMetadata {
             id,
+            name: None,
+            tags: vec![],
             username,
             address,
+            kind,
+            tls_fingerprint,
+            protocol,
             cwd,
+            last_exit_status: None,
+            shell: ShellType::Sh,
+            os: None,
+            arch: None,
+            host: HostInfo::default(),
+            connected_at: Instant::now(),
+            pty: None,
+            term_size: None,
+            display: DisplayMode::default(),
+            encoding: Encoding::default(),
+            input_encoding: Encoding::default(),
+            state: SessionState::Alive,
+            unread: 0,
         };
 
-        Ok(Session { metadata, socket })
+        Session {
+            metadata,
+            socket,
+            scrollback: Scrollback::default(),
+            prompt: None,
+            in_sync: true,
+        }
     }
 
     pub async fn init(&mut self) -> Result<()> {
-        // recv terminal window
-        self.socket
-            .recvuntil("\u{1b}]0;".as_bytes())
-            .await
-            .context("failed to recv a terminal window")?;
+        let config = config::get()?;
 
-        // send whoami
-        self.socket
-            .sendline(b"whoami")
+        // wait for the first response of the shell
+        let syntax = self
+            .detect_syntax(config.handshake_banner_timeout)
             .await
-            .context("failed to send \"whoami\"")?;
+            .context("handshake stage \"banner\" failed")?;
+        self.metadata.shell = ShellType::from_syntax(syntax);
+        info!("cwd: {}", self.metadata.cwd);
 
-        // recv terminal window
-        self.socket
-            .recvuntil(b"whoami\n")
+        // the session is usable without the platform, so failures here are not fatal
+        match self
+            .execute_command_with_timeout(
+                shell::fingerprint_command(syntax),
+                Some(config.handshake_fingerprint_timeout),
+            )
             .await
-            .context("failed to recv terminal window")?;
+        {
+            Ok(output) => {
+                let output = self.metadata.encoding.decode_lossy(&output.stdout);
+                let fingerprint = shell::parse_fingerprint(syntax, &output);
+                self.metadata.shell = fingerprint.shell;
+                self.metadata.os = fingerprint.os;
+                self.metadata.arch = fingerprint.arch;
+                // utf-16le told by the markers is kept, since the console code page
+                // is not what such a shell writes to the pipe
+                if self.metadata.encoding != Encoding::Utf16Le {
+                    if let Some(encoding) = fingerprint.encoding {
+                        self.set_encoding(encoding);
+                    }
+                }
+                if let Some(encoding) = fingerprint.input_encoding {
+                    self.set_input_encoding(encoding);
+                }
+            }
+            Err(e) => warn!("handshake stage \"fingerprint\" failed: {:#}", e),
+        }
+        info!(
+            "shell: {}, platform: {}, encoding: {} (input: {})",
+            self.metadata.shell,
+            self.metadata.platform(),
+            self.metadata.encoding,
+            self.metadata.input_encoding
+        );
 
-        // recv username
         let username = self
-            .socket
-            .recvline()
+            .execute_command_with_timeout(b"whoami", Some(config.handshake_whoami_timeout))
             .await
-            .context("failed to recv username")?;
-
-        // parse username as utf-8
-        let username =
-            String::from_utf8(username.clone()).context("failed to parse username as utf-8")?;
+            .context("handshake stage \"whoami\" failed")?
+            .stdout;
+        // parse username in the encoding of the shell
+        let username = self.metadata.encoding.decode(&username).ok_or_else(|| {
+            anyhow!(
+                "failed to parse username as {} (received: \"{}\")",
+                self.metadata.encoding,
+                username.escape_ascii()
+            )
+        })?;
+        let username = username.lines().next().unwrap_or_default().to_string();
 
         // update username
         self.metadata.username = username;
         info!("username: {}", self.metadata.username);
 
-        // send pwd
-        self.socket
-            .sendline(b"pwd")
+        // like the fingerprint, the session is usable without these
+        match self
+            .execute_command_with_timeout(
+                shell::host_info_command(self.metadata.shell.syntax()),
+                Some(config.handshake_host_timeout),
+            )
             .await
-            .context("failed to send \"pwd\"")?;
+        {
+            Ok(output) => {
+                let output = self.metadata.encoding.decode_lossy(&output.stdout);
+                self.metadata.host = shell::parse_host_info(&output);
+            }
+            Err(e) => warn!("handshake stage \"host\" failed: {:#}", e),
+        }
+        info!(
+            "hostname: {}",
+            self.metadata.host.hostname.as_deref().unwrap_or("unknown")
+        );
 
-        // recv terminal window
-        self.socket
-            .recvuntil(b"pwd\n")
+        // without the prompt, it is kept in the scrollback after every command
+        if let Err(e) = self.learn_prompt().await {
+            warn!("handshake stage \"prompt\" failed: {:#}", e);
+        }
+
+        Ok(())
+    }
+
+    /// Mark the session dead once the connection is closed
+    fn update_state(&mut self) {
+        self.metadata.state = if self.socket.is_closed() {
+            SessionState::Dead
+        } else {
+            SessionState::Alive
+        };
+    }
+
+    /// Change the encoding in which the output of the shell is decoded
+    fn set_encoding(&mut self, encoding: Encoding) {
+        self.metadata.encoding = encoding;
+    }
+
+    /// Change the encoding in which commands and keys are sent
+    fn set_input_encoding(&mut self, encoding: Encoding) {
+        self.metadata.input_encoding = encoding;
+        self.socket.set_encoding(encoding);
+    }
+
+    /// Start the shell on a pseudo terminal with the first spawner which works on the target
+    pub async fn upgrade(&mut self) -> Result<()> {
+        if self.metadata.pty.is_some() {
+            return Ok(());
+        }
+        let syntax = self.metadata.shell.syntax();
+        if syntax != Syntax::Posix && syntax != Syntax::Fish {
+            return Err(anyhow!(
+                "{} sessions can not be upgraded",
+                self.metadata.shell
+            ));
+        }
+
+        let programs = PtySpawner::ALL
+            .iter()
+            .map(|s| s.program())
+            .collect::<Vec<&str>>()
+            .join(" ");
+        let found = self
+            .execute_command_with_timeout(
+                format!("command -v {} 2>/dev/null", programs).as_bytes(),
+                Some(PTY_UPGRADE_TIMEOUT),
+            )
             .await
-            .context("failed to recv terminal window")?;
+            .context("failed to look for pty spawners")?;
+        let found = self.metadata.encoding.decode_lossy(&found.stdout);
+        let spawners = PtySpawner::ALL
+            .into_iter()
+            .filter(|s| {
+                found
+                    .lines()
+                    .any(|l| l.trim().rsplit('/').next() == Some(s.program()))
+            })
+            .collect::<Vec<PtySpawner>>();
+        if spawners.is_empty() {
+            return Err(anyhow!("none of {} found on the target", programs));
+        }
 
-        // recv cwd
-        let cwd = self.socket.recvline().await.context("failed to recv cwd")?;
+        // the output of the spawner and the prompt of the new shell are not for the scrollback,
+        // and the prompt is learned again afterwards
+        self.prompt = None;
+        for spawner in spawners {
+            info!("trying {}", spawner.program());
+            self.socket
+                .sendline(spawner.command(self.metadata.shell.program()).as_bytes())
+                .await
+                .context("failed to send the command")?;
 
-        // parse cwd as utf-8
-        let cwd = String::from_utf8(cwd.clone()).context("failed to parse cwd as utf-8")?;
+            // shells like dash read ahead, so the next line must not arrive before the spawner
+            // takes stdin. the first output (the new prompt or an error) tells that it has started
+            let mut buf = [0; 4096];
+            timeout(PTY_SPAWN_WAIT, self.socket.recv(&mut buf))
+                .await
+                .ok()
+                .transpose()?;
 
-        // update cwd
-        self.metadata.cwd = cwd;
-        info!("cwd: {}", self.metadata.cwd);
+            // the original shell answers instead if the spawner failed.
+            // the interrupt reader must not be started on the pty, where it would be stopped by SIGTTIN
+            self.metadata.pty = Some(spawner);
+            let checked = self
+                .execute_command_with_timeout(b"tty", Some(PTY_UPGRADE_TIMEOUT))
+                .await;
+            if !checked.as_ref().is_ok_and(|o| o.success()) {
+                self.metadata.pty = None;
+            }
+            checked.with_context(|| format!("{} did not respond", spawner.program()))?;
+            if self.metadata.pty.is_none() {
+                warn!("{} failed to start a pseudo terminal", spawner.program());
+                continue;
+            }
+
+            // programs like vim and top need to know the terminal
+            let term = std::env::var("TERM").unwrap_or("xterm-256color".to_string());
+            self.execute_checked_command(
+                format!("export TERM='{}'", term).as_bytes(),
+                Some(PTY_UPGRADE_TIMEOUT),
+            )
+            .await
+            .context("failed to set TERM")?;
+            match terminal::size() {
+                Ok(size) => self.stty_size(size).await?,
+                Err(e) => warn!("{:#}", e),
+            }
+
+            info!("upgraded to a pty with {}", spawner.program());
+            return Ok(());
+        }
 
+        Err(anyhow!("no pty spawner worked"))
+    }
+
+    /// Set the size of the pseudo terminal with stty at the prompt
+    async fn stty_size(&mut self, size: TermSize) -> Result<()> {
+        self.execute_checked_command(
+            format!("stty rows {} cols {}", size.rows, size.cols).as_bytes(),
+            Some(PTY_UPGRADE_TIMEOUT),
+        )
+        .await
+        .context("failed to set the terminal size")?;
+        self.metadata.term_size = Some(size);
         Ok(())
     }
 
-    async fn execute_command(&mut self, command: &[u8]) -> Result<Vec<u8>> {
-        let command = if command.ends_with(b"\n") {
-            &command[0..command.len() - 1]
-        } else {
-            command
+    /// Follow the size of the local terminal.
+    /// Only the python spawner can be resized while a program is running,
+    /// so the others are resized with stty at the prompt, before attaching or running a command.
+    /// A resize while attached is left pending until then.
+    async fn push_term_size(&mut self, attached: bool) -> Result<()> {
+        let spawner = match self.metadata.pty {
+            Some(s) => s,
+            None => return Ok(()),
         };
+        let size = terminal::size()?;
+        if self.metadata.term_size == Some(size) {
+            return Ok(());
+        }
+        match spawner.resize_sequence(size) {
+            Some(sequence) => {
+                self.socket.send(&sequence).await?;
+                self.metadata.term_size = Some(size);
+            }
+            None if !attached => self.stty_size(size).await?,
+            None => {}
+        }
+        Ok(())
+    }
 
-        // execute command
+    /// Forward the local terminal in raw mode to the shell until the escape key is pressed
+    pub async fn interact(&mut self) -> Result<()> {
+        if let Err(e) = self.push_term_size(false).await {
+            warn!("failed to push the terminal size: {:#}", e);
+        }
+        if let Some(spawner) = self.metadata.pty.filter(|s| !s.resizable()) {
+            warn!(
+                "{} can not follow resizes while attached, they are applied when the next command runs",
+                spawner.program()
+            );
+        }
+        let mut resized =
+            signal(SignalKind::window_change()).context("failed to watch the terminal size")?;
+
+        let _raw_mode = RawMode::enable()?;
+        let mut stdin = StdinReader::spawn();
+        let mut stdout = tokio::io::stdout();
+        let mut decoder = self.metadata.encoding.decoder();
+        let mut buf = [0; 4096];
+        loop {
+            tokio::select! {
+                _ = resized.recv() => {
+                    // logging would break the screen in raw mode
+                    self.push_term_size(true).await.ok();
+                }
+                input = stdin.recv() => {
+                    let input = match input {
+                        Some(i) => i?,
+                        None => return Ok(()),
+                    };
+                    match input.iter().position(|&b| b == terminal::ESCAPE_KEY) {
+                        Some(i) => {
+                            self.socket.send(&input[..i]).await?;
+                            return Ok(());
+                        }
+                        None => self.socket.send(&input).await?,
+                    }
+                }
+                received = self.socket.recv(&mut buf) => {
+                    let n = received?;
+                    // the prompt has been shown to the user
+                    self.scrollback.forget_prompt();
+                    match &mut decoder {
+                        Some(decoder) => stdout.write_all(decoder.decode(&buf[..n]).as_bytes()).await?,
+                        None => stdout.write_all(&buf[..n]).await?,
+                    }
+                    stdout.flush().await?;
+                }
+            }
+        }
+    }
+
+    /// Send a posix probe frame first, since a non-interactive posix shell exits on
+    /// a syntax error, and the other syntaxes only when the posix one is not answered.
+    /// Then take the syntax which the shell answers first.
+    /// Probes in the other syntaxes fail to parse, and their leftovers are skipped by later frames.
+    async fn detect_syntax(&mut self, duration: Duration) -> Result<Syntax> {
+        let deadline = Instant::now() + duration;
+        let frames = Syntax::ALL
+            .iter()
+            .map(|&syntax| {
+                (
+                    syntax,
+                    Frame::new(syntax.noop(), syntax, FrameMode::default()),
+                )
+            })
+            .collect::<Vec<(Syntax, Frame)>>();
+        // powershell and `cmd /u` may answer in utf-16le, which is told by the markers
+        let mut candidates = vec![];
+        for (syntax, frame) in &frames {
+            candidates.push((*syntax, Encoding::Utf8, frame.clone()));
+            if matches!(syntax, Syntax::PowerShell | Syntax::Cmd) {
+                let frame = frame.clone().encoded(Encoding::Utf16Le);
+                candidates.push((*syntax, Encoding::Utf16Le, frame));
+            }
+        }
+        let begins = candidates
+            .iter()
+            .map(|(_, _, f)| f.begin.as_slice())
+            .collect::<Vec<&[u8]>>();
+
+        let (posix, others) = frames.split_first().unwrap();
         self.socket
-            .sendline(command)
+            .sendline(&posix.1.line)
             .await
-            .context("failed to send the command")?;
+            .context("failed to send a probe")?;
+        let answered = match self
+            .socket
+            .recvuntil_timeout(&posix.1.begin, POSIX_PROBE_TIMEOUT.min(duration))
+            .await
+        {
+            Ok(_) => Some(0),
+            Err(e) if e.is::<TimedOut>() => None,
+            Err(e) => return Err(e).context("no probe was answered"),
+        };
 
-        // recieve terminal window
-        self.socket
-            .recvuntil(command)
+        let i = match answered {
+            Some(i) => i,
+            None => {
+                for (_, frame) in others {
+                    self.socket
+                        .sendline(&frame.line)
+                        .await
+                        .context("failed to send a probe")?;
+                }
+                // the posix probe may still be answered by a slow shell
+                let (i, _) = self
+                    .socket
+                    .recvuntil_any_timeout(
+                        &begins,
+                        deadline.saturating_duration_since(Instant::now()),
+                    )
+                    .await
+                    .context("no probe was answered")?;
+                i
+            }
+        };
+        let (syntax, encoding, frame) = &candidates[i];
+        if *encoding != Encoding::Utf8 {
+            self.set_encoding(*encoding);
+        }
+
+        self.recvuntil(&self.newline(), Some(duration))
             .await
-            .context("failed to recv a terminal window")?;
+            .context("failed to recv the begin marker")?;
+        self.recvuntil(&frame.end, Some(duration))
+            .await
+            .context("failed to recv the end marker")?;
+        self.finish_frame(Some(duration)).await?;
+
+        Ok(*syntax)
+    }
+
+    async fn recvuntil(&mut self, pattern: &[u8], duration: Option<Duration>) -> Result<Vec<u8>> {
+        match duration {
+            Some(d) => self.socket.recvuntil_timeout(pattern, d).await,
+            None => self.socket.recvuntil(pattern).await,
+        }
+    }
+
+    /// Wrap a command in a frame for the shell of the session
+    fn frame(&self, command: &[u8], mode: FrameMode) -> Frame {
+        let command = command.strip_suffix(b"\n").unwrap_or(command);
+        Frame::new(command, self.metadata.shell.syntax(), mode).encoded(self.metadata.encoding)
+    }
+
+    /// Newline as the shell writes it
+    fn newline(&self) -> Vec<u8> {
+        self.metadata.encoding.ascii(b"\n")
+    }
+
+    /// Send a frame and wait until its output starts.
+    /// Returns the bytes which arrived before the echo of the command,
+    /// i.e. the prompt and output of background jobs, which are kept in the scrollback.
+    async fn start_frame(&mut self, frame: &Frame, duration: Option<Duration>) -> Result<Vec<u8>> {
+        // execute command
         self.socket
-            .recvuntil(b"\n")
+            .sendline(&frame.line)
             .await
-            .context("failed to recv a terminal window")?;
+            .context("failed to send the command")?;
 
-        // recieve output
-        let output = self
-            .socket
-            .recvuntil("\u{1b}]0;".as_bytes())
+        // skip the prompt and the echoed command
+        let skipped = self
+            .recvuntil(&frame.begin, duration)
+            .await
+            .context("failed to recv the begin marker")?;
+        self.recvuntil(&self.newline(), duration)
             .await
-            .context("failed to recv un output")?;
+            .context("failed to recv the begin marker")?;
 
-        // update cwd
-        // send pwd
-        self.socket
-            .sendline(b"pwd")
+        let skipped = skipped
+            .strip_suffix(frame.begin.as_slice())
+            .unwrap_or(&skipped);
+        let echo = self
+            .metadata
+            .encoding
+            .ascii(&frame.line[..ECHO_PREFIX_LEN.min(frame.line.len())]);
+        let before = match skipped.windows(echo.len()).position(|w| w == echo) {
+            Some(i) => &skipped[..i],
+            None => skipped,
+        };
+        let before = before.to_vec();
+        if self.in_sync && self.prompt.is_some() {
+            self.keep(&before);
+        }
+        Ok(before)
+    }
+
+    /// Read the status line after the end marker and update cwd and the exit status
+    async fn finish_frame(&mut self, duration: Option<Duration>) -> Result<FrameStatus> {
+        let line = self
+            .recvuntil(&self.newline(), duration)
             .await
-            .context("failed to send \"pwd\"")?;
+            .context("failed to recv the status line")?;
+        let status = frame::parse_status(&line, self.metadata.encoding)?;
 
-        // recieve terminal window
-        self.socket
-            .recvuntil(b"pwd\n")
+        self.metadata.cwd = status.cwd.clone();
+        self.metadata.last_exit_status = Some(status.exit_status);
+
+        if let Some(prompt) = &self.prompt {
+            self.scrollback.expect(prompt);
+        }
+
+        Ok(status)
+    }
+
+    /// Keep output which arrived while no command was running
+    fn keep(&mut self, data: &[u8]) {
+        self.scrollback.push(data);
+        self.metadata.unread = self.scrollback.unread;
+    }
+
+    /// Learn the prompt which the shell prints after every command, so that only it is
+    /// dropped from the scrollback. Right after a frame, the bytes before the echo of
+    /// the next one are the prompt.
+    async fn learn_prompt(&mut self) -> Result<()> {
+        self.prompt = None;
+        let frame = self.frame(self.metadata.shell.syntax().noop(), FrameMode::default());
+        let last_exit_status = self.metadata.last_exit_status;
+        let prompt = self.start_frame(&frame, Some(PROMPT_TIMEOUT)).await?;
+        self.recvuntil(&frame.end, Some(PROMPT_TIMEOUT))
             .await
-            .context("failed to recv a terminal window")?;
+            .context("failed to recv the end marker")?;
+        self.finish_frame(Some(PROMPT_TIMEOUT)).await?;
+        // the noop is not a command of the user
+        self.metadata.last_exit_status = last_exit_status;
+        self.scrollback.expect(&prompt);
+        self.prompt = Some(prompt);
+        Ok(())
+    }
 
-        // recieve an output
-        let cwd = self
-            .socket
-            .recvline()
+    /// Send a frame and receive its whole output
+    async fn recv_frame(
+        &mut self,
+        frame: &Frame,
+        duration: Option<Duration>,
+    ) -> Result<(Vec<u8>, FrameStatus)> {
+        self.start_frame(frame, duration).await?;
+
+        // recieve output
+        let output = self
+            .recvuntil(&frame.end, duration)
             .await
-            .context("failed to crecv a terminal window")?;
+            .context("failed to recv the end marker")?;
+
+        let status = self.finish_frame(duration).await?;
+        Ok((output, status))
+    }
 
-        let cwd = String::from_utf8(cwd).context("failed to parse cwd as utf-8")?;
-        self.metadata.cwd = cwd;
+    /// Execute a command of sayo itself, like the handshake, and collect its output.
+    /// Its stderr is not captured, so that it needs nothing from the target but the shell.
+    async fn execute_command_with_timeout(
+        &mut self,
+        command: &[u8],
+        duration: Option<Duration>,
+    ) -> Result<CommandOutput> {
+        self.execute_command(command, duration, &CancellationToken::new(), false)
+            .await
+    }
 
+    /// Execute a command of sayo which has to succeed, with its stderr in the error
+    async fn execute_checked_command(
+        &mut self,
+        command: &[u8],
+        duration: Option<Duration>,
+    ) -> Result<CommandOutput> {
+        let output = self
+            .execute_command(command, duration, &CancellationToken::new(), true)
+            .await?;
+        if !output.success() {
+            let stderr = output.stderr.as_deref().unwrap_or_default();
+            return Err(anyhow!(
+                "the command exited with {} (stderr: \"{}\")",
+                output.exit_status,
+                self.metadata.encoding.decode_lossy(stderr).trim_end()
+            ));
+        }
         Ok(output)
     }
 
-    pub async fn execute_command_prettily(&mut self, command: &[u8]) -> Result<()> {
-        let command = if command.ends_with(b"\n") {
-            &command[0..command.len() - 1]
-        } else {
-            command
+    /// Execute a command and collect its output.
+    /// When the shell does not answer in time or the token is cancelled,
+    /// the command is killed and the session is resynchronized before returning the error.
+    async fn execute_command(
+        &mut self,
+        command: &[u8],
+        duration: Option<Duration>,
+        cancel: &CancellationToken,
+        capture_stderr: bool,
+    ) -> Result<CommandOutput> {
+        let started = Instant::now();
+        let mode = FrameMode {
+            interruptible: self.metadata.pty.is_none(),
+            capture_stderr,
         };
+        let frame = self.frame(command, mode);
 
-        // send command
-        self.socket
-            .sendline(command)
+        let received = tokio::select! {
+            received = self.recv_frame(&frame, duration) => received,
+            reason = stopped(None, cancel) => Err(reason),
+        };
+        let (output, status) = match received {
+            Ok(r) => r,
+            Err(e) if cancel.is_cancelled() || e.is::<TimedOut>() => {
+                if let Err(e) = self.abort(&frame).await {
+                    warn!("{:#}", e);
+                }
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+        };
+        let (stdout, stderr) = frame::split_output(&output, &frame, self.metadata.encoding);
+
+        Ok(CommandOutput {
+            stdout,
+            stderr,
+            exit_status: status.exit_status,
+            duration: started.elapsed(),
+            cwd: status.cwd,
+        })
+    }
+
+    /// Execute a command and print its output as it arrives.
+    /// The command is killed when it runs longer than `duration` or the token is cancelled.
+    pub async fn execute_command_prettily(
+        &mut self,
+        command: &[u8],
+        display: DisplayMode,
+        duration: Option<Duration>,
+        cancel: &CancellationToken,
+    ) -> Result<()> {
+        if let Err(e) = self.push_term_size(false).await {
+            warn!("failed to push the terminal size: {:#}", e);
+        }
+        // with a pty, Ctrl-C is simply sent to the terminal
+        let mode = FrameMode {
+            interruptible: self.metadata.pty.is_none(),
+            capture_stderr: false,
+        };
+        let frame = self.frame(command, mode);
+        let stop = stopped(duration, cancel);
+        tokio::pin!(stop);
+
+        tokio::select! {
+            started = self.start_frame(&frame, None) => {
+                started?;
+            }
+            reason = &mut stop => {
+                if let Err(e) = self.abort(&frame).await {
+                    warn!("{:#}", e);
+                }
+                return Err(reason);
+            }
+        }
+
+        // Ctrl-C and Ctrl-Z go to the command instead of killing or stopping sayo
+        let mut keys = KeyCapture::enable()?;
+        let mut printer = LinePrinter::new(&frame.end, false, display, self.metadata.encoding);
+        let mut stop_waiting = false;
+        loop {
+            let mut byte = [0];
+            tokio::select! {
+                // recv and print output line by line
+                received = self.socket.recv(&mut byte) => {
+                    received.context("failed to finish to recv and print an output line by line")?;
+                    if printer.push(byte[0]) {
+                        break;
+                    }
+                }
+                reason = &mut stop => {
+                    printer.flush();
+                    drop(keys);
+                    if let Err(e) = self.abort(&frame).await {
+                        warn!("{:#}", e);
+                    }
+                    return Err(reason);
+                }
+                input = keys.recv() => {
+                    let key = match input?
+                        .into_iter()
+                        .find(|&b| b == terminal::CTRL_C || b == terminal::CTRL_Z)
+                    {
+                        Some(k) => k,
+                        None => continue,
+                    };
+                    if self.forward_key(&frame, key).await? {
+                        printer.flush();
+                        println!("{}", if key == terminal::CTRL_C { "^C" } else { "^Z" });
+                        let synced = if key == terminal::CTRL_C {
+                            self.finish_interrupt().await
+                        } else {
+                            self.resync(RESYNC_TIMEOUT).await
+                        };
+                        if let Err(e) = synced {
+                            warn!("{:#}", e);
+                        }
+                        // 128 + the signal number, like shells do
+                        self.metadata.last_exit_status =
+                            Some(if key == terminal::CTRL_C { 130 } else { 148 });
+                        return Ok(());
+                    }
+                    if stop_waiting && key == terminal::CTRL_C {
+                        printer.flush();
+                        println!("^C");
+                        warn!("stopped waiting for the command, its output will be skipped");
+                        return Ok(());
+                    }
+                    warn!(
+                        "the key can not be forwarded to {} sessions without a pty. press Ctrl-C again to stop waiting",
+                        self.metadata.shell
+                    );
+                    stop_waiting = true;
+                }
+            }
+        }
+        drop(keys);
+
+        self.finish_frame(None).await?;
+
+        Ok(())
+    }
+
+    /// Forward Ctrl-C or Ctrl-Z to the running command.
+    /// Returns false if the session has no way to deliver it.
+    async fn forward_key(&mut self, frame: &Frame, key: u8) -> Result<bool> {
+        if self.metadata.pty.is_some() {
+            self.in_sync = false;
+            self.socket
+                .send(&[key])
+                .await
+                .context("failed to send the key")?;
+            return Ok(true);
+        }
+        match (&frame.interrupt, key) {
+            (Some(interrupt), terminal::CTRL_C) => {
+                self.in_sync = false;
+                self.socket
+                    .sendline(interrupt)
+                    .await
+                    .context("failed to send the interrupt")?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Kill the command of a frame which is not waited for anymore
+    async fn abort(&mut self, frame: &Frame) -> Result<()> {
+        if !self.forward_key(frame, terminal::CTRL_C).await? {
+            return Err(anyhow!(
+                "the command can not be killed in {} sessions without a pty, the session may be out of sync",
+                self.metadata.shell
+            ));
+        }
+        self.finish_interrupt().await?;
+        self.metadata.last_exit_status = Some(130);
+        Ok(())
+    }
+
+    /// Wait for the shell after Ctrl-C was forwarded.
+    /// A command in a pty which ignores SIGINT is stopped with Ctrl-Z instead,
+    /// and then killed by the shell. Without a pty, the reader of the frame does it.
+    async fn finish_interrupt(&mut self) -> Result<()> {
+        if self.metadata.pty.is_none() || self.metadata.shell.syntax() != Syntax::Posix {
+            return self.resync(RESYNC_TIMEOUT).await;
+        }
+        if self.resync(INTERRUPT_WAIT).await.is_ok() {
+            return Ok(());
+        }
+        self.kill_stopped_job()
             .await
-            .context("failed to send the command")?;
+            .context("the command ignored SIGINT")?;
+        self.resync(RESYNC_TIMEOUT).await
+    }
 
-        // recv terminal window
+    /// Stop the foreground job with Ctrl-Z and send SIGTERM and SIGKILL to its process group.
+    /// The job is killed by its process group id, since %% would point at
+    /// another job of the user once it is gone.
+    async fn kill_stopped_job(&mut self) -> Result<()> {
         self.socket
-            .recvuntil(command)
+            .send(&[terminal::CTRL_Z])
             .await
-            .context("failed to recv a terminal window")?;
-        self.socket
-            .recvuntil(b"\n")
+            .context("failed to send the key")?;
+        let frame = self.frame(b"jobs -p %%", FrameMode::default());
+        let (output, _) = self
+            .recv_frame(&frame, Some(INTERRUPT_WAIT))
             .await
-            .context("failed to recv a terminal window")?;
-
-        // recv and print output line by line
-        self.socket
-            .printuntil("\u{1b}]0;".as_bytes(), false)
+            .context("the command was not stopped")?;
+        let pgid = self
+            .metadata
+            .encoding
+            .decode_lossy(&output)
+            .lines()
+            .rev()
+            .find_map(|l| l.trim().parse::<u32>().ok())
+            .ok_or_else(|| anyhow!("no stopped job (received: \"{}\")", output.escape_ascii()))?;
+        let frame = self.frame(
+            format!(
+                "kill -TERM -{0} && kill -CONT -{0}; sleep 1; kill -KILL -{0} 2>/dev/null",
+                pgid
+            )
+            .as_bytes(),
+            FrameMode::default(),
+        );
+        self.recv_frame(&frame, Some(INTERRUPT_WAIT))
             .await
-            .context("failed to finish to recv and print an output line by line")?;
+            .context("failed to kill the stopped job")?;
+        Ok(())
+    }
 
-        // send pwd
-        self.socket
-            .sendline(b"pwd")
-            .await
-            .context("failed to send \"pwd\"")?;
+    /// Check that the shell still answers, without changing the exit status seen by the user
+    async fn probe(&mut self, duration: Duration) -> Result<()> {
+        let last_exit_status = self.metadata.last_exit_status;
+        let frame = self.frame(self.metadata.shell.syntax().noop(), FrameMode::default());
+        let result = self.recv_frame(&frame, Some(duration)).await;
+        self.metadata.last_exit_status = last_exit_status;
+        result.context("the shell did not answer a probe")?;
+        self.in_sync = true;
+        Ok(())
+    }
 
-        // recv terminal window
-        self.socket
-            .recvuntil(b"pwd\n")
+    /// Close the connection, asking the shell to exit first if `polite`
+    async fn close(&mut self, polite: bool) -> Result<()> {
+        let was_closed = self.socket.is_closed();
+        if polite && !was_closed {
+            timeout(CLOSE_TIMEOUT, self.socket.sendline(b"exit"))
+                .await
+                .context("timed out while sending exit")?
+                .context("failed to send exit")?;
+        }
+        let shutdown = timeout(CLOSE_TIMEOUT, self.socket.shutdown()).await;
+        // the connection is already gone, so a failure tells nothing new
+        if was_closed {
+            return Ok(());
+        }
+        shutdown
+            .context("timed out while closing the connection")?
+            .context("failed to close the connection")?;
+        Ok(())
+    }
+
+    /// Skip the rest of an interrupted command until the shell answers a new frame.
+    /// A line sent while the command is dying can be dropped with the input queue of
+    /// the terminal, so the frame is sent again until one is answered.
+    async fn resync(&mut self, duration: Duration) -> Result<()> {
+        let deadline = Instant::now() + duration;
+        loop {
+            let frame = self.frame(self.metadata.shell.syntax().noop(), FrameMode::default());
+            let left = deadline.saturating_duration_since(Instant::now());
+            let last_try = left <= RESYNC_RETRY_INTERVAL;
+            let duration = if last_try {
+                left
+            } else {
+                RESYNC_RETRY_INTERVAL
+            };
+            match self.recv_frame(&frame, Some(duration)).await {
+                Ok(_) => {
+                    self.in_sync = true;
+                    return Ok(());
+                }
+                Err(e) if !last_try && e.is::<TimedOut>() => continue,
+                Err(e) => return Err(e).context("failed to resync with the shell"),
+            }
+        }
+    }
+}
+
+/// Wait until the duration passes or the token is cancelled, and return the reason to stop
+async fn stopped(duration: Option<Duration>, cancel: &CancellationToken) -> anyhow::Error {
+    let expired = async {
+        match duration {
+            Some(d) => {
+                sleep(d).await;
+                anyhow!("the command timed out after {:?}", d)
+            }
+            None => std::future::pending().await,
+        }
+    };
+    tokio::select! {
+        reason = expired => reason,
+        _ = cancel.cancelled() => anyhow!("the command was cancelled"),
+    }
+}
+
+/// Request to the task which owns a session.
+/// The result is sent back through `reply` after the metadata is published.
+enum Request {
+    Execute {
+        command: Vec<u8>,
+        duration: Option<Duration>,
+        cancel: CancellationToken,
+        reply: oneshot::Sender<Result<CommandOutput>>,
+    },
+    ExecutePrettily {
+        command: Vec<u8>,
+        display: DisplayMode,
+        duration: Option<Duration>,
+        cancel: CancellationToken,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    Upgrade {
+        reply: oneshot::Sender<Result<()>>,
+    },
+    Interact {
+        reply: oneshot::Sender<Result<()>>,
+    },
+    SetDisplay {
+        display: DisplayMode,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    SetEncoding {
+        output: Option<Encoding>,
+        input: Option<Encoding>,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    Rename {
+        name: String,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    Tag {
+        tags: Vec<String>,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    Untag {
+        tags: Vec<String>,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    /// Take the output which arrived while no command was running
+    TakeUnread {
+        reply: oneshot::Sender<Result<Vec<u8>>>,
+    },
+    /// Close the connection and end the task
+    Close {
+        polite: bool,
+        reply: oneshot::Sender<Result<()>>,
+    },
+}
+
+/// Handle to the task which owns a session.
+/// Cloning it is cheap, and reading the metadata never waits for a running command.
+#[derive(Clone)]
+struct SessionHandle {
+    requests: mpsc::Sender<Request>,
+    metadata: watch::Receiver<SessionMetadata>,
+}
+
+impl SessionHandle {
+    /// Move the session to its own task
+    fn spawn(session: Session) -> Self {
+        let (requests, receiver) = mpsc::channel(REQUEST_QUEUE_SIZE);
+        let (publisher, metadata) = watch::channel(session.metadata.clone());
+        tokio::spawn(run_session(session, receiver, publisher));
+        Self { requests, metadata }
+    }
+
+    /// Metadata as of the end of the last request
+    fn metadata(&self) -> SessionMetadata {
+        self.metadata.borrow().clone()
+    }
+
+    /// Queue a request and wait for the reply
+    async fn request<T>(
+        &self,
+        request: impl FnOnce(oneshot::Sender<Result<T>>) -> Request,
+    ) -> Result<T> {
+        let (reply, receiver) = oneshot::channel();
+        self.requests
+            .send(request(reply))
             .await
-            .context("failed to recv a terminal window")?;
+            .map_err(|_| anyhow!("the session has been closed"))?;
+        receiver
+            .await
+            .map_err(|_| anyhow!("the session has been closed"))?
+    }
+}
 
-        // recv cwd
-        let cwd = self.socket.recvline().await.context("failed to recv cwd")?;
+/// Serve the requests to a session one by one until every handle is dropped.
+/// While no request is running, the output is kept in the scrollback so that it does not
+/// get mixed into the next command, and the shell is probed if `session.probe-interval`
+/// is set, so that a dead session is noticed without using it.
+async fn run_session(
+    mut session: Session,
+    mut requests: mpsc::Receiver<Request>,
+    metadata: watch::Sender<SessionMetadata>,
+) {
+    let id = session.metadata.id;
 
-        // parse cwd as utf-8
-        let cwd = String::from_utf8(cwd).context("failed to parse cwd as utf-8")?;
+    // reply after publishing the metadata, so that the caller sees the new cwd and exit status
+    macro_rules! reply {
+        ($reply:expr, $result:expr) => {{
+            let result = $result;
+            session.update_state();
+            metadata.send_replace(session.metadata.clone());
+            $reply.send(result).ok();
+        }};
+    }
 
-        // update cwd
-        self.metadata.cwd = cwd;
+    loop {
+        let was_dead = session.metadata.state == SessionState::Dead;
+        let (probe_interval, probe_timeout) = match config::get() {
+            Ok(c) => (c.session_probe_interval, c.session_probe_timeout),
+            Err(_) => (None, Duration::ZERO),
+        };
+        let probe_due = async {
+            match probe_interval {
+                Some(d) => sleep(d).await,
+                None => std::future::pending().await,
+            }
+        };
 
-        Ok(())
+        let request = tokio::select! {
+            request = requests.recv() => match request {
+                Some(r) => Some(r),
+                None => return,
+            },
+            received = session.socket.recv_some(), if !was_dead => {
+                match received {
+                    Ok(data) => session.keep(&data),
+                    Err(e) => warn!("session {}: {:#}", id, e),
+                }
+                None
+            }
+            _ = probe_due, if !was_dead => {
+                if let Err(e) = session.probe(probe_timeout).await {
+                    warn!("session {}: {:#}", id, e);
+                    session.socket.close();
+                }
+                None
+            }
+        };
+
+        if let Some(request) = request {
+            session.metadata.state = SessionState::Busy;
+            metadata.send_replace(session.metadata.clone());
+            // the prompt usually shows the cwd, and a pty brings a new shell
+            let cwd = session.metadata.cwd.clone();
+            let new_shell = matches!(request, Request::Upgrade { .. });
+            match request {
+                Request::Execute {
+                    command,
+                    duration,
+                    cancel,
+                    reply,
+                } => reply!(
+                    reply,
+                    session
+                        .execute_command(&command, duration, &cancel, true)
+                        .await
+                ),
+                Request::ExecutePrettily {
+                    command,
+                    display,
+                    duration,
+                    cancel,
+                    reply,
+                } => reply!(
+                    reply,
+                    session
+                        .execute_command_prettily(&command, display, duration, &cancel)
+                        .await
+                ),
+                Request::Upgrade { reply } => reply!(reply, session.upgrade().await),
+                Request::Interact { reply } => reply!(reply, session.interact().await),
+                Request::SetDisplay { display, reply } => {
+                    session.metadata.display = display;
+                    reply!(reply, Ok(()))
+                }
+                Request::SetEncoding {
+                    output,
+                    input,
+                    reply,
+                } => {
+                    if let Some(encoding) = output {
+                        session.set_encoding(encoding);
+                    }
+                    if let Some(encoding) = input {
+                        session.set_input_encoding(encoding);
+                    }
+                    reply!(reply, Ok(()))
+                }
+                Request::Rename { name, reply } => {
+                    session.metadata.name = Some(name);
+                    reply!(reply, Ok(()))
+                }
+                Request::Tag { tags, reply } => {
+                    for tag in tags {
+                        if !session.metadata.tags.contains(&tag) {
+                            session.metadata.tags.push(tag);
+                        }
+                    }
+                    reply!(reply, Ok(()))
+                }
+                Request::Untag { tags, reply } => {
+                    session.metadata.tags.retain(|t| !tags.contains(t));
+                    reply!(reply, Ok(()))
+                }
+                Request::TakeUnread { reply } => {
+                    let unread = session.scrollback.take_unread();
+                    session.metadata.unread = 0;
+                    reply!(reply, Ok(unread))
+                }
+                Request::Close { polite, reply } => {
+                    // the session is dropped with its buffers when the task ends
+                    let closed = session.close(polite).await;
+                    let removed = remove_session(id);
+                    reply.send(closed.and(removed)).ok();
+                    return;
+                }
+            }
+            if (new_shell || session.metadata.cwd != cwd) && !session.socket.is_closed() {
+                if let Err(e) = session.learn_prompt().await {
+                    warn!("session {}: failed to learn the prompt: {:#}", id, e);
+                }
+            }
+        }
+
+        session.update_state();
+        metadata.send_replace(session.metadata.clone());
+        if was_dead || session.metadata.state != SessionState::Dead {
+            continue;
+        }
+        let remove = config::get()
+            .map(|c| c.session_remove_dead)
+            .unwrap_or(false);
+        if remove && remove_session(id).is_ok() {
+            warn!("session {} is dead and has been removed", id);
+            return;
+        }
+        warn!("session {} is dead", id);
     }
 }
 
-pub async fn new_session(port: u16) -> Result<u16> {
-    let mut sessions = match SESSIONS_ARRAY.lock() {
-        Ok(s) => s,
-        Err(e) => return Err(anyhow!(e.to_string())),
+/// Listen on the address and wait until a reverse shell connects
+pub async fn new_session(
+    bind_address: SocketAddr,
+    tls: Option<&TlsIdentity>,
+    access: &AccessRules,
+) -> Result<u16> {
+    let listener = TcpListener::bind(bind_address)
+        .await
+        .context("failed to bind the address")?;
+    info!("listening on {}", bind_address);
+    if let Some(tls) = tls {
+        info!("certificate fingerprint: {}", tls.fingerprint);
+    }
+
+    let (stream, address) = loop {
+        let (stream, address) = listener
+            .accept()
+            .await
+            .context("failed to accept a connection")?;
+        if access::is_allowed(address.ip(), access)? {
+            break (stream, address);
+        }
+        warn!("rejected a connection from {}", address);
+    };
+
+    accept_session(stream, address, bind_address, tls, false).await
+}
+
+/// Accept a reverse shell connected to the listener bound to `bind_address`.
+/// `sniffed` tells that the protocol was detected from the first bytes of the connection.
+pub async fn accept_session(
+    stream: TcpStream,
+    address: SocketAddr,
+    bind_address: SocketAddr,
+    tls: Option<&TlsIdentity>,
+    sniffed: bool,
+) -> Result<u16> {
+    let kind = SessionKind::Reverse(bind_address);
+    let protocol = |name: &str| {
+        if sniffed {
+            format!("{} (sniffed)", name)
+        } else {
+            name.to_string()
+        }
     };
+    match tls {
+        Some(tls) => {
+            let stream = timeout(TLS_HANDSHAKE_TIMEOUT, tls.acceptor.accept(stream))
+                .await
+                .context("timed out during the tls handshake")?
+                .context("failed to complete the tls handshake")?;
+            let socket = Socket::new(stream);
+            let fingerprint = Some(tls.fingerprint.clone());
+            add_session(
+                socket,
+                address.to_string(),
+                kind,
+                fingerprint,
+                protocol("tls"),
+            )
+            .await
+        }
+        None => {
+            let socket = Socket::new(stream);
+            add_session(socket, address.to_string(), kind, None, protocol("tcp")).await
+        }
+    }
+}
 
-    let mut session = Session::new(port)
+/// Connect to a bind shell listening on the address
+pub async fn connect_session(address: SocketAddr) -> Result<u16> {
+    let stream = timeout(CONNECT_TIMEOUT, TcpStream::connect(address))
         .await
-        .context("failed to create a new session")?;
-    let id = session.metadata.id;
+        .context("timed out while connecting")?
+        .context("failed to connect")?;
+
+    add_session(
+        Socket::new(stream),
+        address.to_string(),
+        SessionKind::Bind,
+        None,
+        "tcp".to_string(),
+    )
+    .await
+}
+
+/// Initialize a session over an established transport and register it
+pub async fn add_session(
+    socket: Socket,
+    address: String,
+    kind: SessionKind,
+    tls_fingerprint: Option<String>,
+    protocol: String,
+) -> Result<u16> {
+    // ids are never reused, even if the initialization fails
+    let id = {
+        let mut next_id = match NEXT_SESSION_ID.lock() {
+            Ok(i) => i,
+            Err(e) => return Err(anyhow!(e.to_string())),
+        };
+        let id = *next_id;
+        *next_id = next_id
+            .checked_add(1)
+            .ok_or_else(|| anyhow!("ran out of session ids"))?;
+        id
+    };
+    let mut session = Session::new(id, socket, address, kind, tls_fingerprint, protocol);
     session
         .init()
         .await
         .context("failed to init the new session")?;
-    sessions.push(session);
-    Ok(id)
-}
 
-pub fn get_metadata(id: u16) -> Result<SessionMetadata> {
-    let sessions = match SESSIONS_ARRAY.lock() {
+    let handle = SessionHandle::spawn(session);
+    let mut sessions = match SESSIONS_ARRAY.lock() {
         Ok(s) => s,
         Err(e) => return Err(anyhow!(e.to_string())),
     };
-    let session = match sessions.iter().find(|x| x.metadata.id == id) {
-        Some(s) => s,
-        None => return Err(anyhow!("session with id {} not found", id)),
-    };
-    Ok(session.metadata.clone())
+    sessions.push(handle);
+    Ok(id)
 }
 
-/// DON"T USE THIS FUNCTION FROM INSIDE MODULE!!
-pub async fn execute_command_prettily(id: u16, command: &[u8]) -> Result<()> {
+/// Drop the handle of a session from the registry
+fn remove_session(id: u16) -> Result<()> {
     let mut sessions = match SESSIONS_ARRAY.lock() {
         Ok(s) => s,
         Err(e) => return Err(anyhow!(e.to_string())),
     };
-    let session = match sessions.iter_mut().find(|x| x.metadata.id == id) {
-        Some(s) => s,
-        None => return Err(anyhow!("session with id {} not found", id)),
-    };
-    session
-        .execute_command_prettily(command)
-        .await
-        .context("failed to execute command prettily")?;
+    sessions.retain(|s| s.metadata().id != id);
     Ok(())
 }
 
-/// DON"T USE THIS FUNCTION FROM INSIDE MODULE!!
-pub async fn execute_command(id: u16, command: &[u8]) -> Result<Vec<u8>> {
-    let mut sessions = match SESSIONS_ARRAY.lock() {
+/// Take the handle of a session out of the registry.
+/// The registry is never locked while waiting for a session.
+fn find_session(id: u16) -> Result<SessionHandle> {
+    let sessions = match SESSIONS_ARRAY.lock() {
         Ok(s) => s,
         Err(e) => return Err(anyhow!(e.to_string())),
     };
-    let session = match sessions.iter_mut().find(|x| x.metadata.id == id) {
-        Some(s) => s,
-        None => return Err(anyhow!("session with id {} not found", id)),
-    };
-    session
-        .execute_command(command)
+    match sessions.iter().find(|x| x.metadata().id == id) {
+        Some(s) => Ok(s.clone()),
+        None => Err(anyhow!("session with id {} not found", id)),
+    }
+}
+
+pub fn get_metadata(id: u16) -> Result<SessionMetadata> {
+    Ok(find_session(id)?.metadata())
+}
+
+/// The command is killed when it runs longer than `duration` or the token is cancelled.
+pub async fn execute_command_prettily(
+    id: u16,
+    command: &[u8],
+    display: DisplayMode,
+    duration: Option<Duration>,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    find_session(id)?
+        .request(|reply| Request::ExecutePrettily {
+            command: command.to_vec(),
+            display,
+            duration,
+            cancel: cancel.clone(),
+            reply,
+        })
+        .await
+        .context("failed to execute command prettily")
+}
+
+/// The command is killed when the shell does not answer within `duration` or the token is cancelled.
+#[allow(dead_code)]
+pub async fn execute_command(
+    id: u16,
+    command: &[u8],
+    duration: Option<Duration>,
+    cancel: &CancellationToken,
+) -> Result<CommandOutput> {
+    find_session(id)?
+        .request(|reply| Request::Execute {
+            command: command.to_vec(),
+            duration,
+            cancel: cancel.clone(),
+            reply,
+        })
         .await
         .context("failed to execute command")
 }
 
-pub fn is_session_exist(id: u16) -> Result<bool> {
+pub async fn upgrade(id: u16) -> Result<()> {
+    find_session(id)?
+        .request(|reply| Request::Upgrade { reply })
+        .await
+}
+
+pub async fn interact(id: u16) -> Result<()> {
+    find_session(id)?
+        .request(|reply| Request::Interact { reply })
+        .await
+}
+
+/// Change how the output of the session is shown
+pub async fn set_display(id: u16, display: DisplayMode) -> Result<()> {
+    find_session(id)?
+        .request(|reply| Request::SetDisplay { display, reply })
+        .await
+}
+
+/// Override the character encodings of the output and the input of the session.
+/// None keeps the current one.
+pub async fn set_encoding(
+    id: u16,
+    output: Option<Encoding>,
+    input: Option<Encoding>,
+) -> Result<()> {
+    find_session(id)?
+        .request(|reply| Request::SetEncoding {
+            output,
+            input,
+            reply,
+        })
+        .await
+}
+
+/// Find the id of a session from its id or name
+pub fn resolve_session(target: &str) -> Result<u16> {
     let sessions = match SESSIONS_ARRAY.lock() {
         Ok(s) => s,
         Err(e) => return Err(anyhow!(e.to_string())),
     };
-    Ok(sessions.iter().any(|x| x.metadata.id == id))
+    let id = target.parse::<u16>().ok();
+    match sessions
+        .iter()
+        .map(|s| s.metadata())
+        .find(|m| Some(m.id) == id || m.name.as_deref() == Some(target))
+    {
+        Some(m) => Ok(m.id),
+        None => Err(anyhow!("session {} not found", target)),
+    }
+}
+
+/// Give a session a name which can be used instead of its id
+pub async fn rename(id: u16, name: &str) -> Result<()> {
+    if name.is_empty() || name.chars().all(|c| c.is_ascii_digit()) || name.starts_with('-') {
+        return Err(anyhow!(
+            "\"{}\" can not be a name, since it looks like an id or an option",
+            name
+        ));
+    }
+    let used_by = match SESSIONS_ARRAY.lock() {
+        Ok(s) => s
+            .iter()
+            .map(|s| s.metadata())
+            .find(|m| m.id != id && m.name.as_deref() == Some(name)),
+        Err(e) => return Err(anyhow!(e.to_string())),
+    };
+    if let Some(m) = used_by {
+        return Err(anyhow!(
+            "\"{}\" is already the name of session {}",
+            name,
+            m.id
+        ));
+    }
+
+    let name = name.to_string();
+    find_session(id)?
+        .request(|reply| Request::Rename { name, reply })
+        .await
+}
+
+/// Add labels to a session
+pub async fn tag(id: u16, tags: Vec<String>) -> Result<()> {
+    find_session(id)?
+        .request(|reply| Request::Tag { tags, reply })
+        .await
+}
+
+/// Remove labels from a session
+pub async fn untag(id: u16, tags: Vec<String>) -> Result<()> {
+    find_session(id)?
+        .request(|reply| Request::Untag { tags, reply })
+        .await
+}
+
+/// Output of a session which arrived while no command was running and has not been shown
+pub async fn take_unread(id: u16) -> Result<Vec<u8>> {
+    find_session(id)?
+        .request(|reply| Request::TakeUnread { reply })
+        .await
+}
+
+/// Close a session and drop it from the list.
+/// With `polite`, the shell is asked to `exit` before the connection is closed.
+/// The session is dropped even if closing fails.
+pub async fn close(id: u16, polite: bool) -> Result<()> {
+    let handle = find_session(id)?;
+    let result = handle
+        .request(|reply| Request::Close { polite, reply })
+        .await;
+    // in case the task is already gone
+    remove_session(id)?;
+    result
+}
+
+/// Close all the sessions whose metadata matches, and return their ids.
+/// A failure to close a session is only warned, since the session is dropped anyway.
+pub async fn close_matching(
+    polite: bool,
+    matches: impl Fn(&SessionMetadata) -> bool,
+) -> Result<Vec<u16>> {
+    let ids = match SESSIONS_ARRAY.lock() {
+        Ok(s) => s
+            .iter()
+            .map(|s| s.metadata())
+            .filter(|m| matches(m))
+            .map(|m| m.id)
+            .collect::<Vec<u16>>(),
+        Err(e) => return Err(anyhow!(e.to_string())),
+    };
+    for &id in &ids {
+        if let Err(e) = close(id, polite).await {
+            warn!("session {}: {:#}", id, e);
+        }
+    }
+    Ok(ids)
 }
 
 /// Make a table(string) of sessions
 pub fn make_session_table() -> Result<String> {
     let sessions = match SESSIONS_ARRAY.lock() {
-        Ok(s) => s,
+        Ok(s) => s
+            .iter()
+            .map(|s| s.metadata())
+            .collect::<Vec<SessionMetadata>>(),
         Err(e) => return Err(anyhow!(e.to_string())),
     };
     use cli_table::{format::Justify, Cell, Style, Table};
+    // details such as the kernel, pid and the whole tls fingerprint are in `sessions info`
     let mut vector = vec![];
     sessions.iter().for_each(|s| {
         vector.push(vec![
-            s.metadata.id.to_string().cell().justify(Justify::Right),
-            s.metadata.username.clone().cell().justify(Justify::Left),
-            s.metadata
-                .address
-                .to_string()
+            s.id.to_string().cell().justify(Justify::Right),
+            s.name
+                .clone()
+                .unwrap_or("-".to_string())
                 .cell()
-                .justify(Justify::Right),
+                .justify(Justify::Left),
+            s.state.name().cell().justify(Justify::Left),
+            if s.unread == 0 {
+                "-".to_string()
+            } else {
+                format!("{}B", s.unread)
+            }
+            .cell()
+            .justify(Justify::Right),
+            format!(
+                "{}@{}",
+                s.username,
+                s.host.hostname.as_deref().unwrap_or("-")
+            )
+            .cell()
+            .justify(Justify::Left),
+            s.address.to_string().cell().justify(Justify::Right),
+            s.shell.cell().justify(Justify::Left),
+            s.platform().cell().justify(Justify::Left),
+            match &s.tls_fingerprint {
+                Some(fingerprint) => fingerprint
+                    .get(..TLS_FINGERPRINT_PREFIX_LEN)
+                    .unwrap_or(fingerprint)
+                    .to_string(),
+                None => "no".to_string(),
+            }
+            .cell()
+            .justify(Justify::Left),
         ]);
     });
     let table = vector
         .table()
         .title(vec![
             "id".cell().bold(true),
-            "username".cell().bold(true),
+            "name".cell().bold(true),
+            "state".cell().bold(true),
+            "unread".cell().bold(true),
+            "user@host".cell().bold(true),
             "address".cell().bold(true),
+            "shell".cell().bold(true),
+            "platform".cell().bold(true),
+            "tls".cell().bold(true),
         ])
         .bold(true);
 
     Ok(table.display().unwrap().to_string())
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncBufReadExt, BufReader, DuplexStream};
+
+    use super::*;
+
+    /// Command in the eval of a posix frame line
+    fn command_of(line: &str) -> String {
+        let mut rest = &line[line.find("eval '").unwrap() + "eval '".len()..];
+        let mut command = String::new();
+        while !rest.starts_with('\'') || rest.starts_with("'\\''") {
+            if rest.starts_with("'\\''") {
+                command.push('\'');
+                rest = &rest[4..];
+            } else {
+                command.push(rest.chars().next().unwrap());
+                rest = &rest[1..];
+            }
+        }
+        command
+    }
+
+    /// Interactive posix shell which answers frames with canned output and prints "$ "
+    /// after every command. It exits on a line in another syntax, like a real one could.
+    async fn fake_shell(stream: DuplexStream) {
+        let (reader, mut writer) = tokio::io::split(stream);
+        let mut lines = BufReader::new(reader).lines();
+        writer.write_all(b"$ ").await.unwrap();
+        while let Ok(Some(line)) = lines.next_line().await {
+            let Some(start) = line.find("'SAYO' '") else {
+                return;
+            };
+            let id = &line[start + "'SAYO' '".len()..][..16];
+            let command = command_of(&line);
+            // a background job writes between the end of the command and the next prompt
+            let mut late = "";
+            let (stdout, stderr, status) = match command.as_str() {
+                ":" => ("", "", 0),
+                "(sleep 0.1; echo late) &" => {
+                    late = "late\n";
+                    ("", "", 0)
+                }
+                "whoami" => ("sayo\n", "", 0),
+                c if c.starts_with("echo \"${BASH_VERSION") => {
+                    ("bash\n/usr/bin/bash\nLinux\nx86_64\nUTF-8\n", "", 0)
+                }
+                c if c.starts_with("echo \"hostname=") => {
+                    ("hostname=target\nuid=1000\npid=42\n", "", 0)
+                }
+                "ls /nowhere" => ("", "ls: /nowhere: No such file or directory\n", 2),
+                c => panic!("unexpected command: {}", c),
+            };
+            let mut output = format!("SAYO{}B\n{}", id, stdout);
+            // stderr goes to the terminal unless the frame captures it
+            if line.contains("mktemp") {
+                output.push_str(&format!("\nSAYO{}S{}", id, stderr));
+            } else {
+                output.push_str(stderr);
+            }
+            output.push_str(&format!("\nSAYO{}E {} /home/sayo\n{}$ ", id, status, late));
+            writer.write_all(output.as_bytes()).await.unwrap();
+        }
+    }
+
+    async fn session() -> Session {
+        let (ours, theirs) = tokio::io::duplex(4096);
+        tokio::spawn(fake_shell(theirs));
+        init(Socket::new(ours)).await
+    }
+
+    /// Session over a local sh without a pty, like a reverse shell piped to sh
+    async fn sh_session() -> (Session, tokio::process::Child) {
+        let mut child = tokio::process::Command::new("sh")
+            .args(["-c", "exec sh 2>&1"])
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .unwrap();
+        let socket = Socket::from_split(child.stdout.take().unwrap(), child.stdin.take().unwrap());
+        (init(socket).await, child)
+    }
+
+    async fn init(socket: Socket) -> Session {
+        let mut session = Session::new(
+            0,
+            socket,
+            "test".to_string(),
+            SessionKind::Bind,
+            None,
+            "tcp".to_string(),
+        );
+        session.init().await.unwrap();
+        session
+    }
+
+    fn scratch_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("sayo-test-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn init_learns_about_the_shell() {
+        let session = session().await;
+        assert_eq!(session.metadata.shell, ShellType::Bash);
+        assert_eq!(session.metadata.os.as_deref(), Some("Linux"));
+        assert_eq!(session.metadata.username, "sayo");
+        assert_eq!(session.metadata.cwd, "/home/sayo");
+        assert_eq!(session.metadata.host.hostname.as_deref(), Some("target"));
+        assert_eq!(session.prompt.as_deref(), Some(b"$ ".as_slice()));
+    }
+
+    #[tokio::test]
+    async fn execute_command_collects_the_output() {
+        let mut session = session().await;
+        let output = session
+            .execute_command(
+                b"whoami",
+                Some(Duration::from_secs(5)),
+                &CancellationToken::new(),
+                false,
+            )
+            .await
+            .unwrap();
+        assert_eq!(output.stdout, b"sayo\n");
+        assert_eq!(output.stderr, None);
+        assert!(output.success());
+        assert_eq!(output.cwd, "/home/sayo");
+
+        let output = session
+            .execute_command(
+                b"ls /nowhere",
+                Some(Duration::from_secs(5)),
+                &CancellationToken::new(),
+                true,
+            )
+            .await
+            .unwrap();
+        assert_eq!(output.stdout, b"");
+        assert_eq!(
+            output.stderr.as_deref(),
+            Some(b"ls: /nowhere: No such file or directory\n".as_slice())
+        );
+        assert_eq!(output.exit_status, 2);
+        assert_eq!(session.metadata.last_exit_status, Some(2));
+
+        // the prompts are not kept
+        assert_eq!(session.scrollback.unread, 0);
+    }
+
+    #[tokio::test]
+    async fn output_before_the_next_command_is_kept() {
+        let mut session = session().await;
+        for command in [b"(sleep 0.1; echo late) &".as_slice(), b":"] {
+            session
+                .execute_command(
+                    command,
+                    Some(Duration::from_secs(5)),
+                    &CancellationToken::new(),
+                    false,
+                )
+                .await
+                .unwrap();
+        }
+        assert!(session.scrollback.take_unread().starts_with(b"late\n"));
+    }
+
+    #[test]
+    fn scrollback_keeps_the_last_bytes() {
+        let mut scrollback = Scrollback::default();
+        scrollback.push(&vec![b'a'; SCROLLBACK_LIMIT]);
+        scrollback.push(b"bc");
+        assert_eq!(scrollback.buffer.len(), SCROLLBACK_LIMIT);
+        assert_eq!(scrollback.unread, SCROLLBACK_LIMIT);
+        assert!(scrollback.take_unread().ends_with(b"abc"));
+    }
+
+    #[test]
+    fn scrollback_takes_only_unread_bytes() {
+        let mut scrollback = Scrollback::default();
+        scrollback.push(b"old\n");
+        assert_eq!(scrollback.take_unread(), b"old\n");
+        scrollback.push(b"new\n");
+        assert_eq!(scrollback.take_unread(), b"new\n");
+        assert_eq!(scrollback.take_unread(), b"");
+    }
+
+    #[test]
+    fn scrollback_drops_the_expected_prompt() {
+        let mut scrollback = Scrollback::default();
+        scrollback.expect(b"user@host:~$ ");
+        // the prompt can be split between reads
+        scrollback.push(b"user@ho");
+        scrollback.push(b"st:~$ done\n");
+        assert_eq!(scrollback.take_unread(), b"done\n");
+
+        // only once
+        scrollback.push(b"user@host:~$ ");
+        assert_eq!(scrollback.take_unread(), b"user@host:~$ ");
+    }
+
+    #[test]
+    fn scrollback_keeps_output_which_is_not_the_prompt() {
+        let mut scrollback = Scrollback::default();
+        scrollback.expect(b"$ ");
+        scrollback.push(b"[1]+  Done\n$ ");
+        assert_eq!(scrollback.take_unread(), b"[1]+  Done\n$ ");
+
+        // a prompt cut in the middle is kept as it arrived
+        scrollback.expect(b"$ ");
+        scrollback.push(b"$");
+        scrollback.push(b"x\n");
+        assert_eq!(scrollback.take_unread(), b"$x\n");
+
+        scrollback.expect(b"$ ");
+        scrollback.push(b"$");
+        scrollback.expect(b"# ");
+        scrollback.push(b"# ");
+        assert_eq!(scrollback.take_unread(), b"$");
+    }
+
+    #[tokio::test]
+    async fn timeout_stops_the_rest_of_the_command_line() {
+        let (mut session, _child) = sh_session().await;
+        let dir = scratch_dir("timeout");
+        let command = format!("sleep 10; touch {}/after", dir.display());
+        let result = session
+            .execute_command(
+                command.as_bytes(),
+                Some(Duration::from_millis(500)),
+                &CancellationToken::new(),
+                false,
+            )
+            .await;
+        assert!(result.is_err());
+        sleep(Duration::from_millis(500)).await;
+        assert!(!dir.join("after").exists());
+
+        // the session is still usable
+        let output = session
+            .execute_command(b"echo ok", None, &CancellationToken::new(), false)
+            .await
+            .unwrap();
+        assert_eq!(output.stdout, b"ok\n");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn cancel_stops_the_rest_of_the_command_line() {
+        let (mut session, _child) = sh_session().await;
+        let dir = scratch_dir("cancel");
+        let command = format!("sleep 10; touch {}/after", dir.display());
+        let cancel = CancellationToken::new();
+        let canceller = cancel.clone();
+        tokio::spawn(async move {
+            sleep(Duration::from_millis(500)).await;
+            canceller.cancel();
+        });
+        let result = session
+            .execute_command(command.as_bytes(), None, &cancel, false)
+            .await;
+        assert!(result.is_err());
+        assert_eq!(session.metadata.last_exit_status, Some(130));
+        sleep(Duration::from_millis(500)).await;
+        assert!(!dir.join("after").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn cwd_is_kept_after_an_interruptible_command() {
+        let (mut session, _child) = sh_session().await;
+        let output = session
+            .execute_command(b"cd / && echo moved", None, &CancellationToken::new(), true)
+            .await
+            .unwrap();
+        assert_eq!(output.stdout, b"moved\n");
+        assert_eq!(output.cwd, "/");
+        let output = session
+            .execute_command(b"pwd; ls /nowhere", None, &CancellationToken::new(), true)
+            .await
+            .unwrap();
+        assert_eq!(output.stdout, b"/\n");
+        assert!(!output.stderr.unwrap().is_empty());
+    }
+}
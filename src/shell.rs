@@ -0,0 +1,304 @@
+use std::fmt;
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+
+use crate::{encoding::Encoding, frame::Syntax, terminal::TermSize};
+
+/// pty.spawn replacement which can resize the terminal while a program is running
+const PTY_SCRIPT: &str = include_str!("pty.py");
+
+/// Shell running on the other side of a session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShellType {
+    Bash,
+    Zsh,
+    Dash,
+    Sh,
+    Busybox,
+    Fish,
+    PowerShell,
+    Cmd,
+}
+
+impl ShellType {
+    /// Guess from the syntax before the shell is fingerprinted
+    pub fn from_syntax(syntax: Syntax) -> Self {
+        match syntax {
+            Syntax::Posix => Self::Sh,
+            Syntax::Fish => Self::Fish,
+            Syntax::PowerShell => Self::PowerShell,
+            Syntax::Cmd => Self::Cmd,
+        }
+    }
+
+    /// Program to run on a pseudo terminal when the session is upgraded
+    pub fn program(&self) -> &'static str {
+        match self {
+            Self::Bash => "bash",
+            Self::Zsh => "zsh",
+            Self::Dash => "dash",
+            Self::Fish => "fish",
+            Self::Sh | Self::Busybox => "sh",
+            Self::PowerShell => "powershell",
+            Self::Cmd => "cmd",
+        }
+    }
+
+    pub fn syntax(&self) -> Syntax {
+        match self {
+            Self::Bash | Self::Zsh | Self::Dash | Self::Sh | Self::Busybox => Syntax::Posix,
+            Self::Fish => Syntax::Fish,
+            Self::PowerShell => Syntax::PowerShell,
+            Self::Cmd => Syntax::Cmd,
+        }
+    }
+}
+
+impl fmt::Display for ShellType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Bash => "bash",
+            Self::Zsh => "zsh",
+            Self::Dash => "dash",
+            Self::Sh => "sh",
+            Self::Busybox => "busybox",
+            Self::Fish => "fish",
+            Self::PowerShell => "powershell",
+            Self::Cmd => "cmd",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Shell type and platform of a session
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fingerprint {
+    pub shell: ShellType,
+    pub os: Option<String>,
+    pub arch: Option<String>,
+    /// encoding of the output, None if it is unknown or not supported
+    pub encoding: Option<Encoding>,
+    /// encoding of the input, which differs from the output on windows
+    pub input_encoding: Option<Encoding>,
+}
+
+/// Command which prints lines used by parse_fingerprint
+pub fn fingerprint_command(syntax: Syntax) -> &'static [u8] {
+    match syntax {
+        // 1: bash or zsh, 2: path of the shell binary, 3: os, 4: architecture, 5: charset
+        Syntax::Posix => {
+            b"echo \"${BASH_VERSION:+bash}${ZSH_VERSION:+zsh}\"; \
+            echo \"$(readlink /proc/$$/exe 2>/dev/null || ps -p $$ -o comm= 2>/dev/null)\"; \
+            echo \"$(uname -s 2>/dev/null)\"; echo \"$(uname -m 2>/dev/null)\"; \
+            echo \"$(locale charmap 2>/dev/null)\""
+        }
+        Syntax::Fish => {
+            b"echo (uname -s 2>/dev/null); echo (uname -m 2>/dev/null); \
+            echo (locale charmap 2>/dev/null)"
+        }
+        Syntax::PowerShell => {
+            b"[System.Environment]::OSVersion.VersionString; $env:PROCESSOR_ARCHITECTURE; \
+            [Console]::OutputEncoding.CodePage; [Console]::InputEncoding.CodePage"
+        }
+        // chcp prints "Active code page: 932" in the language of the system
+        Syntax::Cmd => b"ver & echo %PROCESSOR_ARCHITECTURE% & chcp",
+    }
+}
+
+pub fn parse_fingerprint(syntax: Syntax, output: &str) -> Fingerprint {
+    let lines = output
+        .lines()
+        .map(|l| l.trim().to_string())
+        .collect::<Vec<String>>();
+    let line = |i: usize| lines.get(i).filter(|l| !l.is_empty()).cloned();
+
+    match syntax {
+        Syntax::Posix => {
+            let shell = match (line(0).as_deref(), line(1)) {
+                (Some("bash"), _) => ShellType::Bash,
+                (Some("zsh"), _) => ShellType::Zsh,
+                (_, Some(path)) if path.ends_with("busybox") => ShellType::Busybox,
+                (_, Some(path)) if path.ends_with("dash") => ShellType::Dash,
+                (_, Some(path)) if path.ends_with("bash") => ShellType::Bash,
+                (_, Some(path)) if path.ends_with("zsh") => ShellType::Zsh,
+                _ => ShellType::Sh,
+            };
+            let encoding = line(4).and_then(|c| Encoding::from_charmap(&c));
+            Fingerprint {
+                shell,
+                os: line(2),
+                arch: line(3),
+                encoding,
+                input_encoding: encoding,
+            }
+        }
+        Syntax::Fish => {
+            let encoding = line(2).and_then(|c| Encoding::from_charmap(&c));
+            Fingerprint {
+                shell: ShellType::Fish,
+                os: line(0),
+                arch: line(1),
+                encoding,
+                input_encoding: encoding,
+            }
+        }
+        Syntax::PowerShell | Syntax::Cmd => {
+            // `ver` prints an empty line first
+            let mut lines = lines.into_iter().filter(|l| !l.is_empty());
+            let code_page = |line: Option<String>| {
+                line.and_then(|l| l.rsplit([' ', ':']).next()?.parse::<u32>().ok())
+                    .and_then(Encoding::from_code_page)
+            };
+            let os = lines.next();
+            let arch = lines.next();
+            let encoding = code_page(lines.next());
+            // cmd has a single code page for both
+            let input_encoding = match syntax {
+                Syntax::PowerShell => code_page(lines.next()),
+                _ => encoding,
+            };
+            Fingerprint {
+                shell: ShellType::from_syntax(syntax),
+                os,
+                arch,
+                encoding,
+                input_encoding,
+            }
+        }
+    }
+}
+
+/// Details of the host besides the platform.
+/// Any of them can be missing on a given host, so they are printed as `key=value` lines
+/// instead of lines at fixed positions like the fingerprint.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HostInfo {
+    pub hostname: Option<String>,
+    /// name and version of the distribution or the windows edition (e.g. "Ubuntu 22.04.4 LTS")
+    pub distribution: Option<String>,
+    /// kernel release, or the version number of windows
+    pub kernel: Option<String>,
+    /// user id, or the security identifier of the user on windows
+    pub uid: Option<String>,
+    pub gid: Option<String>,
+    pub groups: Vec<String>,
+    /// process id of the shell
+    pub pid: Option<u32>,
+}
+
+/// Command which prints lines used by parse_host_info
+pub fn host_info_command(syntax: Syntax) -> &'static [u8] {
+    match syntax {
+        Syntax::Posix => {
+            b"echo \"hostname=$(hostname 2>/dev/null || uname -n 2>/dev/null)\"; \
+            echo \"distribution=$(. /etc/os-release 2>/dev/null && echo \"$PRETTY_NAME\")\"; \
+            echo \"kernel=$(uname -r 2>/dev/null)\"; \
+            echo \"uid=$(id -u 2>/dev/null)\"; echo \"gid=$(id -g 2>/dev/null)\"; \
+            echo \"groups=$(id -Gn 2>/dev/null | tr ' ' ',')\"; echo \"pid=$$\""
+        }
+        Syntax::Fish => {
+            b"echo hostname=(hostname 2>/dev/null; or uname -n 2>/dev/null); \
+            echo distribution=(sh -c '. /etc/os-release && echo \"$PRETTY_NAME\"' 2>/dev/null); \
+            echo kernel=(uname -r 2>/dev/null); \
+            echo uid=(id -u 2>/dev/null); echo gid=(id -g 2>/dev/null); \
+            echo groups=(id -Gn 2>/dev/null | string replace -a ' ' ','); echo pid=$fish_pid"
+        }
+        Syntax::PowerShell => {
+            b"\"hostname=$([Environment]::MachineName)\"; \
+            \"distribution=$((Get-CimInstance Win32_OperatingSystem -ErrorAction Ignore).Caption)\"; \
+            \"kernel=$([Environment]::OSVersion.Version)\"; \
+            $sayo_i = try { [Security.Principal.WindowsIdentity]::GetCurrent() } catch { $null }; \
+            \"uid=$($sayo_i.User.Value)\"; \
+            \"groups=$(($sayo_i.Groups | % { try { $_.Translate([Security.Principal.NTAccount]).Value } catch {} }) -join ',')\"; \
+            \"pid=$PID\""
+        }
+        // the version is the 4th word of "Microsoft Windows [Version 10.0.19045.3803]"
+        Syntax::Cmd => {
+            b"echo hostname=%COMPUTERNAME%& \
+            (for /f \"tokens=4 delims=[] \" %v in ('ver') do @echo kernel=%v)& \
+            (for /f \"tokens=2 delims=,\" %s in ('whoami /user /fo csv /nh') do @echo uid=%~s)"
+        }
+    }
+}
+
+pub fn parse_host_info(output: &str) -> HostInfo {
+    let mut info = HostInfo::default();
+    for (key, value) in output.lines().filter_map(|l| l.split_once('=')) {
+        let value = value.trim();
+        if value.is_empty() {
+            continue;
+        }
+        match key.trim() {
+            "hostname" => info.hostname = Some(value.to_string()),
+            "distribution" => info.distribution = Some(value.to_string()),
+            "kernel" => info.kernel = Some(value.to_string()),
+            "uid" => info.uid = Some(value.to_string()),
+            "gid" => info.gid = Some(value.to_string()),
+            "groups" => info.groups = value.split(',').map(|g| g.trim().to_string()).collect(),
+            "pid" => info.pid = value.parse().ok(),
+            _ => {}
+        }
+    }
+    info
+}
+
+/// Program which runs a shell on a pseudo terminal
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PtySpawner {
+    Python3,
+    Python,
+    Script,
+    Socat,
+    Expect,
+}
+
+impl PtySpawner {
+    /// In the order they are tried
+    pub const ALL: [PtySpawner; 5] = [
+        Self::Python3,
+        Self::Python,
+        Self::Script,
+        Self::Socat,
+        Self::Expect,
+    ];
+
+    pub fn program(&self) -> &'static str {
+        match self {
+            Self::Python3 => "python3",
+            Self::Python => "python",
+            Self::Script => "script",
+            Self::Socat => "socat",
+            Self::Expect => "expect",
+        }
+    }
+
+    /// Command line which starts `shell` on a pseudo terminal on top of the current shell
+    pub fn command(&self, shell: &str) -> String {
+        match self {
+            Self::Python3 | Self::Python => format!(
+                "{} -c 'import base64; exec(base64.b64decode(\"{}\"))' {}",
+                self.program(),
+                BASE64.encode(PTY_SCRIPT),
+                shell
+            ),
+            Self::Script => format!("script -qc {} /dev/null", shell),
+            Self::Socat => format!("socat - exec:'{} -i',pty,stderr,setsid,sigint,sane", shell),
+            Self::Expect => format!("expect -c 'spawn {}; interact'", shell),
+        }
+    }
+
+    /// Whether the terminal can be resized while a program is running
+    pub fn resizable(&self) -> bool {
+        match self {
+            Self::Python3 | Self::Python => true,
+            Self::Script | Self::Socat | Self::Expect => false,
+        }
+    }
+
+    /// Bytes which resize the terminal from the input while attached,
+    /// or None if the size can only be changed with stty at the prompt
+    pub fn resize_sequence(&self, size: TermSize) -> Option<Vec<u8>> {
+        self.resizable()
+            .then(|| format!("\x1b]sayo;{};{}\x07", size.rows, size.cols).into_bytes())
+    }
+}
@@ -0,0 +1,183 @@
+use std::{
+    fmt,
+    io::IsTerminal,
+    os::fd::AsRawFd,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use nix::{
+    poll::{poll, PollFd, PollFlags},
+    sys::termios::{self, LocalFlags, SetArg, SpecialCharacterIndices, Termios},
+};
+use tokio::sync::mpsc;
+
+/// Ctrl-] returns from raw mode to the sayo prompt
+pub const ESCAPE_KEY: u8 = 0x1d;
+
+pub const CTRL_C: u8 = 0x03;
+pub const CTRL_Z: u8 = 0x1a;
+
+/// How often the stdin reader checks if it has been stopped
+const STDIN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Size of a terminal in characters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TermSize {
+    pub rows: u16,
+    pub cols: u16,
+}
+
+impl fmt::Display for TermSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}x{}", self.cols, self.rows)
+    }
+}
+
+/// Size of the local terminal
+pub fn size() -> Result<TermSize> {
+    let (cols, rows) = crossterm::terminal::size().context("failed to get the terminal size")?;
+    Ok(TermSize { rows, cols })
+}
+
+/// Local terminal in raw mode. The previous settings are restored when this is dropped.
+pub struct RawMode {}
+
+impl RawMode {
+    pub fn enable() -> Result<Self> {
+        crossterm::terminal::enable_raw_mode().context("failed to enable raw mode")?;
+        Ok(Self {})
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        crossterm::terminal::disable_raw_mode().ok();
+    }
+}
+
+/// Local terminal which delivers Ctrl-C and Ctrl-Z as bytes instead of signals while a
+/// remote command is running. Unlike raw mode, the output is still processed as usual.
+/// Does nothing when stdin is not a terminal.
+pub struct KeyCapture {
+    original: Option<Termios>,
+    reader: Option<StdinReader>,
+}
+
+impl KeyCapture {
+    pub fn enable() -> Result<Self> {
+        let stdin = std::io::stdin();
+        if !stdin.is_terminal() {
+            return Ok(Self {
+                original: None,
+                reader: None,
+            });
+        }
+
+        let original = termios::tcgetattr(&stdin).context("failed to get terminal attributes")?;
+        let mut captured = original.clone();
+        captured
+            .local_flags
+            .remove(LocalFlags::ICANON | LocalFlags::ECHO | LocalFlags::ISIG);
+        captured.control_chars[SpecialCharacterIndices::VMIN as usize] = 1;
+        captured.control_chars[SpecialCharacterIndices::VTIME as usize] = 0;
+        termios::tcsetattr(&stdin, SetArg::TCSANOW, &captured)
+            .context("failed to set terminal attributes")?;
+
+        Ok(Self {
+            original: Some(original),
+            reader: Some(StdinReader::spawn()),
+        })
+    }
+
+    /// Wait for the next input. Never returns if stdin is not a terminal or closed.
+    pub async fn recv(&mut self) -> Result<Vec<u8>> {
+        if let Some(reader) = &mut self.reader {
+            if let Some(input) = reader.recv().await {
+                return input;
+            }
+            self.reader = None;
+        }
+        std::future::pending().await
+    }
+}
+
+impl Drop for KeyCapture {
+    fn drop(&mut self) {
+        // stop reading before rustyline takes stdin back
+        self.reader = None;
+        if let Some(original) = &self.original {
+            termios::tcsetattr(std::io::stdin(), SetArg::TCSANOW, original).ok();
+        }
+    }
+}
+
+/// Read stdin on a thread and pass the bytes through a channel.
+///
+/// Reading stdin cannot be cancelled, so the thread polls it and stops without reading
+/// once this is dropped. Otherwise a pending read would steal the next line from rustyline.
+pub struct StdinReader {
+    receiver: mpsc::Receiver<Result<Vec<u8>>>,
+    stopped: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl StdinReader {
+    pub fn spawn() -> Self {
+        let (sender, receiver) = mpsc::channel(16);
+        let stopped = Arc::new(AtomicBool::new(false));
+        let handle = std::thread::spawn({
+            let stopped = stopped.clone();
+            move || read_stdin(sender, stopped)
+        });
+        Self {
+            receiver,
+            stopped,
+            handle: Some(handle),
+        }
+    }
+
+    /// Wait for the next input. Returns None when stdin is closed.
+    pub async fn recv(&mut self) -> Option<Result<Vec<u8>>> {
+        self.receiver.recv().await
+    }
+}
+
+impl Drop for StdinReader {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // unblock the thread if it is waiting for space in the channel
+        self.receiver.close();
+        if let Some(handle) = self.handle.take() {
+            handle.join().ok();
+        }
+    }
+}
+
+fn read_stdin(sender: mpsc::Sender<Result<Vec<u8>>>, stopped: Arc<AtomicBool>) {
+    let stdin = std::io::stdin();
+    let mut buf = [0; 4096];
+    while !stopped.load(Ordering::SeqCst) {
+        let mut fds = [PollFd::new(&stdin, PollFlags::POLLIN)];
+        let result = match poll(&mut fds, STDIN_POLL_INTERVAL.as_millis() as i32) {
+            Ok(0) => continue,
+            Ok(_) if stopped.load(Ordering::SeqCst) => break,
+            Ok(_) => match nix::unistd::read(stdin.as_raw_fd(), &mut buf) {
+                Ok(0) => break,
+                Ok(n) => Ok(buf[..n].to_vec()),
+                Err(e) => Err(e).context("failed to read stdin"),
+            },
+            Err(nix::errno::Errno::EINTR) => continue,
+            Err(e) => Err(e).context("failed to poll stdin"),
+        };
+        let is_err = result.is_err();
+        if sender.blocking_send(result).is_err() || is_err {
+            break;
+        }
+    }
+}
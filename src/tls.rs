@@ -0,0 +1,84 @@
+use std::{fs::File, io::BufReader, path::Path, sync::Arc};
+
+use anyhow::{anyhow, Context, Result};
+use sha2::{Digest, Sha256};
+use tokio_rustls::{
+    rustls::{
+        pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
+        ServerConfig,
+    },
+    TlsAcceptor,
+};
+
+/// Certificate and key used by TLS listeners
+#[derive(Clone)]
+pub struct TlsIdentity {
+    pub acceptor: TlsAcceptor,
+    /// sha256 fingerprint of the certificate in the same format as `openssl x509 -fingerprint`
+    pub fingerprint: String,
+}
+
+impl std::fmt::Debug for TlsIdentity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TlsIdentity")
+            .field("fingerprint", &self.fingerprint)
+            .finish()
+    }
+}
+
+impl TlsIdentity {
+    /// Generate a self-signed certificate
+    pub fn self_signed() -> Result<Self> {
+        let certified_key = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])
+            .context("failed to generate a self-signed certificate")?;
+        let cert = certified_key.cert.der().clone();
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(
+            certified_key.key_pair.serialize_der(),
+        ));
+        Self::new(vec![cert], key)
+    }
+
+    /// Load a certificate chain and a private key from pem files
+    pub fn from_pem_files(cert_path: &Path, key_path: &Path) -> Result<Self> {
+        let mut cert_reader = BufReader::new(
+            File::open(cert_path)
+                .with_context(|| format!("failed to open {}", cert_path.display()))?,
+        );
+        let certs = rustls_pemfile::certs(&mut cert_reader)
+            .collect::<Result<Vec<_>, _>>()
+            .context("failed to parse the certificate")?;
+        if certs.is_empty() {
+            return Err(anyhow!("no certificate found in {}", cert_path.display()));
+        }
+
+        let mut key_reader = BufReader::new(
+            File::open(key_path)
+                .with_context(|| format!("failed to open {}", key_path.display()))?,
+        );
+        let key = rustls_pemfile::private_key(&mut key_reader)
+            .context("failed to parse the private key")?
+            .ok_or_else(|| anyhow!("no private key found in {}", key_path.display()))?;
+
+        Self::new(certs, key)
+    }
+
+    fn new(certs: Vec<CertificateDer<'static>>, key: PrivateKeyDer<'static>) -> Result<Self> {
+        let fingerprint = fingerprint(&certs[0]);
+        let config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .context("failed to make a tls config")?;
+        Ok(Self {
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+            fingerprint,
+        })
+    }
+}
+
+fn fingerprint(cert: &CertificateDer) -> String {
+    Sha256::digest(cert.as_ref())
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<String>>()
+        .join(":")
+}
@@ -0,0 +1,367 @@
+use std::{collections::VecDeque, fmt, time::Duration};
+
+use anyhow::{anyhow, Context, Result};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    time::{timeout_at, Instant},
+};
+
+use crate::{
+    display::{DisplayMode, Renderer},
+    encoding::Encoding,
+};
+
+/// Byte stream which a session can run over.
+/// Anything readable and writable asynchronously can be a transport:
+/// tcp, tls, unix sockets, in-memory pipes (`tokio::io::duplex`) and so on.
+pub trait Transport: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Transport for T {}
+
+pub struct Socket {
+    reader: Box<dyn AsyncRead + Send + Unpin>,
+    writer: Box<dyn AsyncWrite + Send + Unpin>,
+    /// encoding of sent bytes. Received bytes are kept as they are, and decoded when shown
+    encoding: Encoding,
+    /// bytes received but not read yet
+    pending: VecDeque<u8>,
+    /// set when reading or writing fails, after which the socket is not used anymore
+    closed: bool,
+}
+
+impl fmt::Debug for Socket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Socket").finish_non_exhaustive()
+    }
+}
+
+impl Socket {
+    pub fn new<T: Transport + 'static>(transport: T) -> Self {
+        let (reader, writer) = tokio::io::split(transport);
+        Self::from_split(reader, writer)
+    }
+
+    /// Make a socket from separated read and write sides such as stdout and stdin of a child process
+    pub fn from_split<R, W>(reader: R, writer: W) -> Self
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+        W: AsyncWrite + Send + Unpin + 'static,
+    {
+        Self {
+            reader: Box::new(reader),
+            writer: Box::new(writer),
+            encoding: Encoding::default(),
+            pending: VecDeque::new(),
+            closed: false,
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Give up the connection, e.g. when the shell stopped answering
+    pub fn close(&mut self) {
+        self.closed = true;
+    }
+
+    /// Close the write side, which tells the shell that no more input comes
+    pub async fn shutdown(&mut self) -> Result<()> {
+        self.closed = true;
+        self.writer.shutdown().await?;
+        Ok(())
+    }
+
+    /// Change the encoding of the shell. Sent bytes are encoded from utf-8 from now on.
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        self.encoding = encoding;
+    }
+
+    pub async fn send(&mut self, data: &[u8]) -> Result<()> {
+        if self.closed {
+            return Err(anyhow!("connection closed"));
+        }
+        let data = self.encoding.encode(data)?;
+        let written = async {
+            self.writer.write_all(&data).await?;
+            self.writer.flush().await
+        }
+        .await;
+        if written.is_err() {
+            self.closed = true;
+        }
+        written?;
+        Ok(())
+    }
+
+    pub async fn sendline(&mut self, data: &[u8]) -> Result<()> {
+        self.send(data).await?;
+        self.send(b"\n").await?;
+        Ok(())
+    }
+
+    /// Read from the transport and keep the bytes until they are received
+    async fn fill(&mut self) -> Result<()> {
+        if self.closed {
+            return Err(anyhow!("connection closed"));
+        }
+        let mut buf = [0; 4096];
+        let n = match self.reader.read(&mut buf).await {
+            Ok(0) => {
+                self.closed = true;
+                return Err(anyhow!("connection closed"));
+            }
+            Ok(n) => n,
+            Err(e) => {
+                self.closed = true;
+                return Err(e.into());
+            }
+        };
+        self.pending.extend(&buf[..n]);
+        Ok(())
+    }
+
+    /// Take everything received so far, waiting for the next read if there is nothing
+    pub async fn recv_some(&mut self) -> Result<Vec<u8>> {
+        if self.pending.is_empty() {
+            self.fill().await?;
+        }
+        Ok(self.pending.drain(..).collect())
+    }
+
+    async fn recv_byte(&mut self) -> Result<u8> {
+        loop {
+            if let Some(b) = self.pending.pop_front() {
+                return Ok(b);
+            }
+            self.fill().await?;
+        }
+    }
+
+    /// Receive whatever has arrived. Fails when the connection is closed.
+    pub async fn recv(&mut self, buf: &mut [u8]) -> Result<usize> {
+        while self.pending.is_empty() {
+            self.fill().await?;
+        }
+        let n = buf.len().min(self.pending.len());
+        for (b, p) in buf.iter_mut().zip(self.pending.drain(..n)) {
+            *b = p;
+        }
+        Ok(n)
+    }
+
+    pub async fn recvuntil(&mut self, pattern: &[u8]) -> Result<Vec<u8>> {
+        let mut buf = vec![];
+        loop {
+            buf.push(self.recv_byte().await?);
+            if buf.ends_with(pattern) {
+                break;
+            }
+        }
+        Ok(buf)
+    }
+
+    /// Same as recvuntil, but gives up when the pattern does not arrive in time.
+    /// The error contains the bytes received so far.
+    pub async fn recvuntil_timeout(
+        &mut self,
+        pattern: &[u8],
+        duration: Duration,
+    ) -> Result<Vec<u8>> {
+        let (_, buf) = self.recvuntil_any_timeout(&[pattern], duration).await?;
+        Ok(buf)
+    }
+
+    /// Receive until one of the patterns arrives and return its index with the received bytes
+    pub async fn recvuntil_any_timeout(
+        &mut self,
+        patterns: &[&[u8]],
+        duration: Duration,
+    ) -> Result<(usize, Vec<u8>)> {
+        let deadline = Instant::now() + duration;
+        let mut buf = vec![];
+        loop {
+            match timeout_at(deadline, self.recv_byte()).await {
+                Ok(Ok(b)) => buf.push(b),
+                Ok(Err(e)) => {
+                    return Err(anyhow!(e)).with_context(|| {
+                        format!("connection closed (received: \"{}\")", buf.escape_ascii())
+                    })
+                }
+                Err(_) => {
+                    return Err(TimedOut {
+                        duration,
+                        received: buf,
+                    }
+                    .into())
+                }
+            }
+            if let Some(i) = patterns.iter().position(|p| buf.ends_with(p)) {
+                return Ok((i, buf));
+            }
+        }
+    }
+
+    /// Print received lines until the pattern arrives.
+    /// The bytes are shown as the shell writes them in `encoding`.
+    #[allow(dead_code)]
+    pub async fn printuntil(
+        &mut self,
+        pattern: &[u8],
+        print_pattern: bool,
+        mode: DisplayMode,
+        encoding: Encoding,
+    ) -> Result<()> {
+        let mut printer = LinePrinter::new(pattern, print_pattern, mode, encoding);
+        loop {
+            if printer.push(self.recv_byte().await?) {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Receive until the newline of the shell and strip it
+    #[allow(dead_code)]
+    pub async fn recvline(&mut self, encoding: Encoding) -> Result<Vec<u8>> {
+        let newline = encoding.ascii(b"\n");
+        let mut line = self.recvuntil(&newline).await?;
+        line.truncate(line.len() - newline.len());
+        Ok(line)
+    }
+}
+
+/// Error of waiting for a pattern which did not arrive in time
+#[derive(Debug)]
+pub struct TimedOut {
+    pub duration: Duration,
+    /// bytes received before giving up
+    pub received: Vec<u8>,
+}
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "timed out after {:?} (received: \"{}\")",
+            self.duration,
+            self.received.escape_ascii()
+        )
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+/// Print bytes line by line until the pattern arrives.
+/// A line is held back until the next one completes, so that the newline right before
+/// the pattern is treated as a separator and not printed as an empty line.
+pub struct LinePrinter<'a> {
+    pattern: &'a [u8],
+    print_pattern: bool,
+    /// newline and carriage return as the shell writes them
+    newline: Vec<u8>,
+    carriage_return: Vec<u8>,
+    buf: Vec<u8>,
+    last_line_index: usize,
+    held_line: Option<Vec<u8>>,
+    renderer: Renderer,
+}
+
+impl<'a> LinePrinter<'a> {
+    pub fn new(
+        pattern: &'a [u8],
+        print_pattern: bool,
+        mode: DisplayMode,
+        encoding: Encoding,
+    ) -> Self {
+        Self {
+            pattern,
+            print_pattern,
+            newline: encoding.ascii(b"\n"),
+            carriage_return: encoding.ascii(b"\r"),
+            buf: vec![],
+            last_line_index: 0,
+            held_line: None,
+            renderer: Renderer::new(mode, encoding),
+        }
+    }
+
+    /// Feed a received byte. Returns true when the pattern has arrived and everything is printed.
+    pub fn push(&mut self, byte: u8) -> bool {
+        self.buf.push(byte);
+
+        if self.buf.ends_with(self.pattern) {
+            let end = if self.print_pattern {
+                self.buf.len()
+            } else {
+                self.buf.len() - self.pattern.len()
+            };
+            let mut rest = self.held_line.take().unwrap_or_default();
+            rest.extend_from_slice(&self.buf[self.last_line_index..end]);
+            if !self.print_pattern && rest.ends_with(&self.newline) {
+                rest.truncate(rest.len() - self.newline.len());
+                // a pty turns the newline into \r\n
+                if rest.ends_with(&self.carriage_return) {
+                    rest.truncate(rest.len() - self.carriage_return.len());
+                }
+            }
+            if !rest.is_empty() {
+                self.renderer.line(&rest);
+            }
+            self.renderer.finish();
+            return true;
+        }
+
+        if self.buf.ends_with(&self.newline) {
+            if let Some(line) = self
+                .held_line
+                .replace(self.buf[self.last_line_index..].to_vec())
+            {
+                self.renderer.line(&line);
+            }
+            self.last_line_index = self.buf.len();
+        }
+        false
+    }
+
+    /// Print what has been received so far when the pattern will not arrive
+    pub fn flush(&mut self) {
+        let mut rest = self.held_line.take().unwrap_or_default();
+        rest.extend_from_slice(&self.buf[self.last_line_index..]);
+        self.last_line_index = self.buf.len();
+        if !rest.is_empty() {
+            self.renderer.line(&rest);
+        }
+        self.renderer.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn recvline_strips_the_newline_of_the_shell() {
+        let (ours, mut theirs) = tokio::io::duplex(64);
+        let mut socket = Socket::new(ours);
+        theirs.write_all(b"first\nsecond\n").await.unwrap();
+        assert_eq!(socket.recvline(Encoding::Utf8).await.unwrap(), b"first");
+        assert_eq!(socket.recvline(Encoding::Utf8).await.unwrap(), b"second");
+
+        theirs.write_all(b"o\0k\0\n\0").await.unwrap();
+        assert_eq!(socket.recvline(Encoding::Utf16Le).await.unwrap(), b"o\0k\0");
+    }
+
+    #[tokio::test]
+    async fn send_encodes_in_the_encoding_of_the_input() {
+        let (ours, mut theirs) = tokio::io::duplex(64);
+        let mut socket = Socket::new(ours);
+        socket.set_encoding(Encoding::Cp932);
+        socket.send("こ\n".as_bytes()).await.unwrap();
+        socket.set_encoding(Encoding::Utf16Le);
+        socket.send("こ\n".as_bytes()).await.unwrap();
+
+        let mut received = [0; 7];
+        theirs.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"\x82\xb1\n\x53\x30\n\0");
+    }
+}
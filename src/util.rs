@@ -1,3 +1,6 @@
+use std::{net::IpAddr, time::Duration};
+
+use anyhow::{anyhow, Context, Result};
 use log::error;
 
 pub mod color {
@@ -36,7 +39,24 @@ pub mod color {
 }
 
 pub fn tidy_usage(c: &str, d: &str) -> String {
-    format!("  {}{}{}", c, " ".repeat(23 - c.len()), d)
+    format!(
+        "  {}{}{}",
+        c,
+        " ".repeat(23usize.saturating_sub(c.len()).max(1)),
+        d
+    )
+}
+
+/// Format a duration like "1h02m03s"
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 3600 {
+        format!("{}h{:02}m{:02}s", secs / 3600, secs / 60 % 60, secs % 60)
+    } else if secs >= 60 {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{}s", secs)
+    }
 }
 
 pub fn print_error(msg: &str, e: anyhow::Error) {
@@ -73,3 +93,34 @@ pub fn print_error(msg: &str, e: anyhow::Error) {
 
     print(&error_list);
 }
+
+/// Resolve a bind address given as an ip address (`0.0.0.0`, `[::]`, ...)
+/// or as a network interface name (`tun0`, ...)
+pub fn resolve_bind_address(address: &str) -> Result<IpAddr> {
+    let trimmed = address
+        .strip_prefix('[')
+        .and_then(|a| a.strip_suffix(']'))
+        .unwrap_or(address);
+
+    if let Ok(ip) = trimmed.parse::<IpAddr>() {
+        return Ok(ip);
+    }
+
+    let interfaces = if_addrs::get_if_addrs().context("failed to get network interfaces")?;
+    let mut addresses = interfaces
+        .iter()
+        .filter(|i| i.name == address)
+        .map(|i| i.ip())
+        .collect::<Vec<IpAddr>>();
+
+    // prefer ipv4 because most of reverse shell payloads are written for ipv4
+    addresses.sort_by_key(|ip| ip.is_ipv6());
+
+    match addresses.first() {
+        Some(ip) => Ok(*ip),
+        None => Err(anyhow!(
+            "\"{}\" is neither an ip address nor a network interface",
+            address
+        )),
+    }
+}